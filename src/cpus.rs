@@ -0,0 +1,184 @@
+use core::mem;
+use errno::errno;
+use std::error::Error;
+use std::fs;
+
+/// Parses a CPU list in the kernel cpulist format, e.g. "0-3,8,10-15".
+pub fn parse_cpulist(list: &str) -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut cpus = Vec::new();
+
+    for item in list.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+        match item.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.trim().parse()?;
+                let last: usize = last.trim().parse()?;
+                if first > last {
+                    return Err(format!("invalid CPU range \"{}\"", item).into());
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(item.parse()?),
+        }
+    }
+
+    if cpus.is_empty() {
+        return Err(format!("empty CPU list \"{}\"", list).into());
+    }
+
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Formats a sorted CPU list back into the kernel cpulist format.
+pub fn format_cpulist(cpus: &[usize]) -> String {
+    if cpus.is_empty() {
+        return "none".to_string();
+    }
+
+    let mut ranges: Vec<String> = Vec::new();
+    let mut iter = cpus.iter().copied().peekable();
+
+    while let Some(first) = iter.next() {
+        let mut last = first;
+        while iter.peek() == Some(&(last + 1)) {
+            last = iter.next().unwrap();
+        }
+
+        if first == last {
+            ranges.push(first.to_string());
+        } else {
+            ranges.push(format!("{}-{}", first, last));
+        }
+    }
+
+    ranges.join(",")
+}
+
+/// CPUs the process is allowed to run on, as reported by sched_getaffinity.
+pub fn allowed_cpus() -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+
+    let ret = unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) };
+    if ret < 0 {
+        return Err(Box::new(errno()));
+    }
+
+    Ok((0..libc::CPU_SETSIZE as usize)
+        .filter(|&cpu| unsafe { libc::CPU_ISSET(cpu, &set) })
+        .collect())
+}
+
+/// Mount point of the cgroup v2 hierarchy, if any.
+fn cgroup2_mount() -> Option<String> {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").ok()?;
+
+    mountinfo.lines().find_map(|line| {
+        let (mount, fs) = line.split_once(" - ")?;
+        if fs.split_whitespace().next()? == "cgroup2" {
+            mount.split_whitespace().nth(4).map(String::from)
+        } else {
+            None
+        }
+    })
+}
+
+/// CPUs granted to our cgroup by the cpuset controller (cgroup v2 only).
+/// Returns None if the information is not available.
+fn cgroup_cpus() -> Option<Vec<usize>> {
+    let cgroups = fs::read_to_string("/proc/self/cgroup").ok()?;
+    let path = cgroups.lines().find_map(|line| line.strip_prefix("0::"))?;
+
+    let file = format!("{}{}/cpuset.cpus.effective", cgroup2_mount()?, path);
+    parse_cpulist(&fs::read_to_string(file).ok()?).ok()
+}
+
+/// CPUs we can actually use: the inherited affinity mask restricted to the
+/// cpuset of our cgroup.
+pub fn usable_cpus() -> Result<Vec<usize>, Box<dyn Error>> {
+    let mut cpus = allowed_cpus()?;
+
+    if let Some(cgroup) = cgroup_cpus() {
+        cpus.retain(|cpu| cgroup.contains(cpu));
+    }
+
+    if cpus.is_empty() {
+        return Err("no usable CPUs".into());
+    }
+
+    Ok(cpus)
+}
+
+/// Parses a CPU list and checks that every CPU in it is in `allowed`.
+/// "all" selects every allowed CPU.
+pub fn parse_allowed_cpulist(list: &str, allowed: &[usize]) -> Result<Vec<usize>, Box<dyn Error>> {
+    if list == "all" {
+        return Ok(allowed.to_vec());
+    }
+
+    let cpus = parse_cpulist(list)?;
+
+    match cpus.iter().find(|cpu| !allowed.contains(cpu)) {
+        Some(cpu) => Err(format!(
+            "CPU {} is not in the allowed CPU set ({})",
+            cpu,
+            format_cpulist(allowed)
+        )
+        .into()),
+        None => Ok(cpus),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_cpulist() {
+        assert_eq!(parse_cpulist("0").unwrap(), [0]);
+        assert_eq!(
+            parse_cpulist("0-3,8,10-12").unwrap(),
+            [0, 1, 2, 3, 8, 10, 11, 12]
+        );
+        assert_eq!(parse_cpulist(" 2 - 3 , 1 ").unwrap(), [1, 2, 3]);
+        assert_eq!(parse_cpulist("4-4").unwrap(), [4]);
+    }
+
+    #[test]
+    fn sorts_and_dedups_cpulist() {
+        assert_eq!(parse_cpulist("8,0-2,1,2-3").unwrap(), [0, 1, 2, 3, 8]);
+    }
+
+    #[test]
+    fn rejects_bad_cpulist() {
+        assert!(parse_cpulist("").is_err());
+        assert!(parse_cpulist(",").is_err());
+        assert!(parse_cpulist("3-1").is_err());
+        assert!(parse_cpulist("a").is_err());
+        assert!(parse_cpulist("1-").is_err());
+        assert!(parse_cpulist("-1").is_err());
+    }
+
+    #[test]
+    fn formats_cpulist() {
+        assert_eq!(format_cpulist(&[]), "none");
+        assert_eq!(format_cpulist(&[5]), "5");
+        assert_eq!(format_cpulist(&[0, 1, 2, 3, 8, 10, 11]), "0-3,8,10-11");
+        assert_eq!(format_cpulist(&[1, 3, 5]), "1,3,5");
+    }
+
+    #[test]
+    fn formats_what_it_parses() {
+        let list = "0-3,8,10-15";
+        assert_eq!(format_cpulist(&parse_cpulist(list).unwrap()), list);
+    }
+
+    #[test]
+    fn checks_allowed_cpus() {
+        let allowed = [0, 1, 2, 4];
+
+        assert_eq!(parse_allowed_cpulist("all", &allowed).unwrap(), allowed);
+        assert_eq!(parse_allowed_cpulist("1-2", &allowed).unwrap(), [1, 2]);
+        assert!(parse_allowed_cpulist("2-4", &allowed).is_err());
+    }
+}
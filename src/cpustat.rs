@@ -0,0 +1,466 @@
+use crate::stats::Unit;
+use scheduler::{set_self_priority, Which};
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Time a CPU spent busy and in total, in clock ticks.
+#[derive(Clone, Copy, Default)]
+pub struct CpuTimes {
+    pub busy: u64,
+    pub total: u64,
+}
+
+/// Parses the per-CPU lines of /proc/stat.
+pub fn parse_stat(stat: &str) -> Vec<(usize, CpuTimes)> {
+    stat.lines()
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            let cpu = fields.next()?.strip_prefix("cpu")?.parse().ok()?;
+            let ticks: Vec<u64> = fields.map_while(|f| f.parse().ok()).collect();
+
+            // user nice system idle iowait irq softirq steal; guest time is
+            // already accounted in user and nice
+            let idle = ticks.get(3)? + ticks.get(4).unwrap_or(&0);
+            let total: u64 = ticks.iter().take(8).sum();
+            Some((
+                cpu,
+                CpuTimes {
+                    busy: total - idle,
+                    total,
+                },
+            ))
+        })
+        .collect()
+}
+
+/// Runqueue and load balancing counters of a CPU from /proc/schedstat.
+#[derive(Clone, Copy, Default)]
+pub struct SchedCounters {
+    /// Time tasks spent waiting on the runqueue, in nanoseconds.
+    pub run_delay: u64,
+    /// Load balancing attempts, in every domain and idle state.
+    pub lb_count: u64,
+    /// Attempts that found an imbalance but could not move anything.
+    pub lb_failed: u64,
+    /// Tasks pulled to this CPU by the load balancer.
+    pub lb_gained: u64,
+}
+
+/// Parses /proc/schedstat. Returns None for versions we do not know the
+/// layout of.
+pub fn parse_schedstat(schedstat: &str) -> Option<Vec<(usize, SchedCounters)>> {
+    let mut lines = schedstat.lines();
+    let version: u32 = lines
+        .next()?
+        .strip_prefix("version ")?
+        .trim()
+        .parse()
+        .ok()?;
+
+    // Fields per idle type in the domain lines, the index of lb_gained and
+    // the fields before the counters: the cpumask, preceded by the domain
+    // name since version 17
+    let (per_type, gained, header) = match version {
+        15 | 16 => (8, 4, 1),
+        17 => (11, 7, 2),
+        _ => return None,
+    };
+
+    let mut cpus: Vec<(usize, SchedCounters)> = Vec::new();
+
+    for line in lines {
+        let mut fields = line.split_whitespace();
+        let Some(name) = fields.next() else {
+            continue;
+        };
+
+        if let Some(cpu) = name.strip_prefix("cpu") {
+            let values: Vec<u64> = fields.map_while(|f| f.parse().ok()).collect();
+            let counters = SchedCounters {
+                run_delay: *values.get(7)?,
+                ..Default::default()
+            };
+            cpus.push((cpu.parse().ok()?, counters));
+        } else if name.starts_with("domain") {
+            // Domain lines follow the line of the CPU they belong to
+            let (_, counters) = cpus.last_mut()?;
+            let values: Vec<u64> = fields.skip(header).map_while(|f| f.parse().ok()).collect();
+
+            for idle_type in values.chunks(per_type).take(3) {
+                if idle_type.len() < per_type {
+                    return None;
+                }
+                counters.lb_count += idle_type[0];
+                counters.lb_failed += idle_type[2];
+                counters.lb_gained += idle_type[gained];
+            }
+        }
+    }
+
+    Some(cpus)
+}
+
+/// What a set of CPUs did during one sampling period.
+struct Sample {
+    /// Time since the start of the run.
+    elapsed: Duration,
+    /// Busy percentage of every CPU.
+    busy: Vec<f64>,
+    /// Counters of every CPU during the period, if /proc/schedstat exists.
+    sched: Option<Vec<SchedCounters>>,
+}
+
+/// Reads the counters of `cpus` in the given order. CPUs missing from the
+/// files read as zero.
+fn read_counters(cpus: &[usize]) -> (Vec<CpuTimes>, Option<Vec<SchedCounters>>) {
+    let stat = fs::read_to_string("/proc/stat")
+        .map(|s| parse_stat(&s))
+        .unwrap_or_default();
+    let sched = fs::read_to_string("/proc/schedstat")
+        .ok()
+        .and_then(|s| parse_schedstat(&s));
+
+    let times = cpus
+        .iter()
+        .map(|cpu| {
+            stat.iter()
+                .find(|(c, _)| c == cpu)
+                .map(|&(_, t)| t)
+                .unwrap_or_default()
+        })
+        .collect();
+    let sched = sched.map(|sched| {
+        cpus.iter()
+            .map(|cpu| {
+                sched
+                    .iter()
+                    .find(|(c, _)| c == cpu)
+                    .map(|&(_, s)| s)
+                    .unwrap_or_default()
+            })
+            .collect()
+    });
+
+    (times, sched)
+}
+
+fn mean(values: &[f64]) -> f64 {
+    if values.is_empty() {
+        return 0.0;
+    }
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+fn stddev(values: &[f64]) -> f64 {
+    let avg = mean(values);
+    mean(
+        &values
+            .iter()
+            .map(|v| (v - avg) * (v - avg))
+            .collect::<Vec<_>>(),
+    )
+    .sqrt()
+}
+
+/// Difference between the busiest and the idlest CPU.
+fn spread(values: &[f64]) -> f64 {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if values.is_empty() {
+        0.0
+    } else {
+        max - min
+    }
+}
+
+/// Utilization and runqueue statistics of every CPU over the run.
+pub struct CpuStatsReport {
+    interval: Duration,
+    cpus: Vec<usize>,
+    samples: Vec<Sample>,
+}
+
+impl CpuStatsReport {
+    fn average_busy(&self, index: usize) -> f64 {
+        mean(
+            &self
+                .samples
+                .iter()
+                .map(|s| s.busy[index])
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    fn total_sched(&self, index: usize) -> Option<SchedCounters> {
+        self.samples
+            .iter()
+            .try_fold(SchedCounters::default(), |mut total, s| {
+                let counters = s.sched.as_ref()?[index];
+                total.run_delay += counters.run_delay;
+                total.lb_count += counters.lb_count;
+                total.lb_failed += counters.lb_failed;
+                total.lb_gained += counters.lb_gained;
+                Some(total)
+            })
+    }
+
+    /// Average and maximum over the samples of the max-min utilization and
+    /// the utilization stddev across CPUs.
+    fn imbalance(&self) -> ((f64, f64), (f64, f64)) {
+        let spreads: Vec<f64> = self.samples.iter().map(|s| spread(&s.busy)).collect();
+        let stddevs: Vec<f64> = self.samples.iter().map(|s| stddev(&s.busy)).collect();
+        let max = |v: &[f64]| v.iter().copied().fold(0.0, f64::max);
+
+        (
+            (mean(&spreads), max(&spreads)),
+            (mean(&stddevs), max(&stddevs)),
+        )
+    }
+
+    pub fn write(&self, out: &mut dyn Write, unit: Unit) -> io::Result<()> {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "CPU utilization over {} samples of {:?}:",
+            self.samples.len(),
+            self.interval
+        )?;
+        writeln!(
+            out,
+            "{:>6} {:>8} {:>16} {:>10} {:>10} {:>10}",
+            "CPU", "Busy %", "Run delay", "LB count", "LB failed", "LB gained"
+        )?;
+
+        for (i, cpu) in self.cpus.iter().enumerate() {
+            let (delay, count, failed, gained) = match self.total_sched(i) {
+                Some(s) => (
+                    unit.format(s.run_delay as f64),
+                    s.lb_count.to_string(),
+                    s.lb_failed.to_string(),
+                    s.lb_gained.to_string(),
+                ),
+                None => (
+                    "n/a".to_string(),
+                    "n/a".to_string(),
+                    "n/a".to_string(),
+                    "n/a".to_string(),
+                ),
+            };
+            writeln!(
+                out,
+                "{:>6} {:>8.2} {:>16} {:>10} {:>10} {:>10}",
+                cpu,
+                self.average_busy(i),
+                delay,
+                count,
+                failed,
+                gained
+            )?;
+        }
+
+        let ((avg_spread, max_spread), (avg_stddev, max_stddev)) = self.imbalance();
+        writeln!(
+            out,
+            "Utilization imbalance: max-min {:.2}% avg, {:.2}% worst; stddev {:.2}% avg, {:.2}% worst",
+            avg_spread, max_spread, avg_stddev, max_stddev
+        )
+    }
+
+    pub fn to_json(&self) -> Value {
+        let cpus: Vec<Value> = self
+            .cpus
+            .iter()
+            .enumerate()
+            .map(|(i, cpu)| {
+                let sched = self.total_sched(i);
+                json!({
+                    "cpu": cpu,
+                    "busy_pct": self.average_busy(i),
+                    "run_delay_ns": sched.map(|s| s.run_delay),
+                    "lb_count": sched.map(|s| s.lb_count),
+                    "lb_failed": sched.map(|s| s.lb_failed),
+                    "lb_gained": sched.map(|s| s.lb_gained),
+                })
+            })
+            .collect();
+
+        let samples: Vec<Value> = self
+            .samples
+            .iter()
+            .map(|s| {
+                json!({
+                    "elapsed_ns": s.elapsed.as_nanos() as u64,
+                    "busy_pct": s.busy,
+                    "run_delay_ns": s.sched.as_ref().map(|sched| {
+                        sched.iter().map(|c| c.run_delay).collect::<Vec<_>>()
+                    }),
+                })
+            })
+            .collect();
+
+        let ((avg_spread, max_spread), (avg_stddev, max_stddev)) = self.imbalance();
+
+        json!({
+            "interval_ns": self.interval.as_nanos() as u64,
+            "cpus": cpus,
+            "imbalance": {
+                "avg_spread_pct": avg_spread,
+                "max_spread_pct": max_spread,
+                "avg_stddev_pct": avg_stddev,
+                "max_stddev_pct": max_stddev,
+            },
+            "samples": samples,
+        })
+    }
+}
+
+/// Low priority thread sampling /proc/stat and /proc/schedstat.
+pub struct Sampler {
+    done: Arc<AtomicBool>,
+    thread_handle: thread::JoinHandle<CpuStatsReport>,
+}
+
+impl Sampler {
+    pub fn new(cpus: Vec<usize>, interval: Duration) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let sampler_done = done.clone();
+
+        let thread_handle = thread::spawn(move || {
+            set_self_priority(Which::Process, 19).unwrap();
+
+            let start = Instant::now();
+            let mut next = start + interval;
+            let (mut prev_times, mut prev_sched) = read_counters(&cpus);
+            let mut samples = Vec::new();
+
+            loop {
+                let stop = sampler_done.load(Ordering::Acquire);
+
+                let now = Instant::now();
+                if !stop && now < next {
+                    thread::park_timeout(next - now);
+                    continue;
+                }
+                next += interval;
+
+                // The period cut short by the end of the run is sampled too,
+                // unless it is too short to have any ticks in it
+                let (times, sched) = read_counters(&cpus);
+                if stop
+                    && times
+                        .iter()
+                        .zip(&prev_times)
+                        .all(|(t, p)| t.total == p.total)
+                {
+                    break;
+                }
+                let busy = times
+                    .iter()
+                    .zip(&prev_times)
+                    .map(|(t, p)| {
+                        let total = t.total.saturating_sub(p.total);
+                        if total == 0 {
+                            0.0
+                        } else {
+                            t.busy.saturating_sub(p.busy) as f64 * 100.0 / total as f64
+                        }
+                    })
+                    .collect();
+                let delta = sched.as_ref().zip(prev_sched.as_ref()).map(|(s, p)| {
+                    s.iter()
+                        .zip(p)
+                        .map(|(s, p)| SchedCounters {
+                            run_delay: s.run_delay.saturating_sub(p.run_delay),
+                            lb_count: s.lb_count.saturating_sub(p.lb_count),
+                            lb_failed: s.lb_failed.saturating_sub(p.lb_failed),
+                            lb_gained: s.lb_gained.saturating_sub(p.lb_gained),
+                        })
+                        .collect()
+                });
+
+                samples.push(Sample {
+                    elapsed: start.elapsed(),
+                    busy,
+                    sched: delta,
+                });
+                prev_times = times;
+                prev_sched = sched;
+
+                if stop {
+                    break;
+                }
+            }
+
+            CpuStatsReport {
+                interval,
+                cpus,
+                samples,
+            }
+        });
+
+        Sampler {
+            done,
+            thread_handle,
+        }
+    }
+
+    pub fn stop(self) -> CpuStatsReport {
+        self.done.store(true, Ordering::Release);
+        self.thread_handle.thread().unpark();
+        self.thread_handle.join().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_stat() {
+        let cpus = parse_stat(include_str!("../tests/fixtures/stat"));
+
+        let times: Vec<(usize, u64, u64)> = cpus
+            .iter()
+            .map(|(cpu, t)| (*cpu, t.busy, t.total))
+            .collect();
+        assert_eq!(times, [(0, 3172, 11318565), (1, 1990, 11318449)]);
+    }
+
+    fn check_schedstat(schedstat: &str) {
+        let cpus = parse_schedstat(schedstat).unwrap();
+
+        let counters: Vec<(usize, u64, u64, u64, u64)> = cpus
+            .iter()
+            .map(|(cpu, s)| (*cpu, s.run_delay, s.lb_count, s.lb_failed, s.lb_gained))
+            .collect();
+        assert_eq!(
+            counters,
+            [(0, 123456789, 123, 12, 1200), (1, 246913578, 123, 12, 1206)]
+        );
+    }
+
+    #[test]
+    fn parses_schedstat_v15() {
+        check_schedstat(include_str!("../tests/fixtures/schedstat-v15"));
+    }
+
+    #[test]
+    fn parses_schedstat_v16() {
+        check_schedstat(include_str!("../tests/fixtures/schedstat-v16"));
+    }
+
+    #[test]
+    fn parses_schedstat_v17() {
+        check_schedstat(include_str!("../tests/fixtures/schedstat-v17"));
+    }
+
+    #[test]
+    fn rejects_unknown_schedstat_version() {
+        assert!(parse_schedstat("version 14\ntimestamp 0\n").is_none());
+    }
+}
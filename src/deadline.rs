@@ -0,0 +1,94 @@
+use core::mem;
+use errno::errno;
+use std::error::Error;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+// Not in the libc crate for every target
+const SCHED_DEADLINE: u32 = 6;
+/// Send SIGXCPU when the task runs past its runtime.
+const SCHED_FLAG_DL_OVERRUN: u64 = 0x04;
+
+/// struct sched_attr, as of SCHED_ATTR_SIZE_VER0.
+#[repr(C)]
+struct SchedAttr {
+    size: u32,
+    sched_policy: u32,
+    sched_flags: u64,
+    sched_nice: i32,
+    sched_priority: u32,
+    sched_runtime: u64,
+    sched_deadline: u64,
+    sched_period: u64,
+}
+
+/// SCHED_DEADLINE reservation of the timer threads.
+#[derive(Clone, Copy, Debug)]
+pub struct DeadlineConfig {
+    pub runtime: Duration,
+    pub deadline: Duration,
+    pub period: Duration,
+}
+
+impl DeadlineConfig {
+    /// Checks what the kernel would otherwise refuse with a bare EINVAL.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.runtime < Duration::from_micros(1) {
+            return Err("the deadline runtime must be at least 1us".into());
+        }
+        if self.runtime > self.deadline || self.deadline > self.period {
+            return Err(
+                "the deadline parameters must satisfy runtime <= deadline <= period".into(),
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Switches the calling thread to SCHED_DEADLINE.
+pub fn set_self_deadline(config: &DeadlineConfig) -> Result<(), Box<dyn Error>> {
+    let attr = SchedAttr {
+        size: mem::size_of::<SchedAttr>() as u32,
+        sched_policy: SCHED_DEADLINE,
+        sched_flags: SCHED_FLAG_DL_OVERRUN,
+        sched_nice: 0,
+        sched_priority: 0,
+        sched_runtime: config.runtime.as_nanos() as u64,
+        sched_deadline: config.deadline.as_nanos() as u64,
+        sched_period: config.period.as_nanos() as u64,
+    };
+
+    let ret = unsafe { libc::syscall(libc::SYS_sched_setattr, 0, &attr as *const SchedAttr, 0) };
+    if ret < 0 {
+        let e = errno();
+        // Admission control only accepts tasks allowed on their whole root
+        // domain, and only as long as there is bandwidth left
+        let hint = if e.0 == libc::EPERM {
+            " (does the affinity mask cover every CPU of the root domain?)"
+        } else if e.0 == libc::EBUSY {
+            " (is the deadline bandwidth of the root domain used up?)"
+        } else {
+            ""
+        };
+        return Err(format!("cannot set SCHED_DEADLINE: {}{}", e, hint).into());
+    }
+
+    Ok(())
+}
+
+/// Counts the runtime overruns of every SCHED_DEADLINE thread. The kernel
+/// reports them with a SIGXCPU to the whole process, so they cannot be
+/// told apart per thread.
+pub fn count_runtime_overruns() -> Result<Arc<AtomicU64>, Box<dyn Error>> {
+    let count = Arc::new(AtomicU64::new(0));
+    let handler_count = count.clone();
+
+    unsafe {
+        signal_hook::low_level::register(libc::SIGXCPU, move || {
+            handler_count.fetch_add(1, Ordering::Relaxed);
+        })?;
+    }
+
+    Ok(count)
+}
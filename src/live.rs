@@ -0,0 +1,252 @@
+use crate::report::{unix_time_ns, Output};
+use crate::stats::Unit;
+use scheduler::{set_self_priority, Which};
+use serde_json::json;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+use std::{cmp, thread};
+
+/// Values below this are counted in their own bucket.
+const LINEAR_BUCKETS: usize = 64;
+/// Buckets per power of two above LINEAR_BUCKETS, about 3% precision.
+const SUB_BUCKET_BITS: u32 = 5;
+const SUB_BUCKETS: usize = 1 << SUB_BUCKET_BITS;
+const NUM_BUCKETS: usize = LINEAR_BUCKETS + (64 - 6) * SUB_BUCKETS;
+
+fn bucket_index(value: u64) -> usize {
+    if value < LINEAR_BUCKETS as u64 {
+        return value as usize;
+    }
+
+    let exp = 63 - value.leading_zeros();
+    let shift = exp - SUB_BUCKET_BITS;
+    let mantissa = (value >> shift) as usize & (SUB_BUCKETS - 1);
+    LINEAR_BUCKETS + (exp as usize - 6) * SUB_BUCKETS + mantissa
+}
+
+/// Highest value that falls in the given bucket.
+fn bucket_value(index: usize) -> u64 {
+    if index < LINEAR_BUCKETS {
+        return index as u64;
+    }
+
+    let exp = (index - LINEAR_BUCKETS) / SUB_BUCKETS + 6;
+    let mantissa = ((index - LINEAR_BUCKETS) % SUB_BUCKETS) as u64;
+    let shift = exp as u32 - SUB_BUCKET_BITS;
+    ((SUB_BUCKETS as u64 + mantissa) << shift) + ((1 << shift) - 1)
+}
+
+/// Statistics a timer thread publishes while it runs. Everything is updated
+/// with relaxed atomics, so the timer thread never blocks on the reporter.
+pub struct LiveStats {
+    count: AtomicU64,
+    sum: AtomicU64,
+    overruns: AtomicU64,
+    /// Reset by the reporter every time it reads them.
+    interval_min: AtomicU64,
+    interval_max: AtomicU64,
+    buckets: Vec<AtomicU64>,
+}
+
+impl LiveStats {
+    pub fn new() -> Self {
+        LiveStats {
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            overruns: AtomicU64::new(0),
+            interval_min: AtomicU64::new(u64::MAX),
+            interval_max: AtomicU64::new(0),
+            buckets: (0..NUM_BUCKETS).map(|_| AtomicU64::new(0)).collect(),
+        }
+    }
+
+    /// Publishes a latency sample, in nanoseconds, and the overruns that
+    /// came with it.
+    pub fn record(&self, latency: u64, overrun: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(latency, Ordering::Relaxed);
+        self.overruns.fetch_add(overrun, Ordering::Relaxed);
+        self.interval_min.fetch_min(latency, Ordering::Relaxed);
+        self.interval_max.fetch_max(latency, Ordering::Relaxed);
+        self.buckets[bucket_index(latency)].fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Statistics over a set of samples, either since the last report or since
+/// the start of the run.
+struct Window {
+    count: u64,
+    sum: u64,
+    overruns: u64,
+    min: u64,
+    max: u64,
+    buckets: Vec<u64>,
+}
+
+impl Window {
+    fn new() -> Self {
+        Window {
+            count: 0,
+            sum: 0,
+            overruns: 0,
+            min: u64::MAX,
+            max: 0,
+            buckets: vec![0; NUM_BUCKETS],
+        }
+    }
+
+    fn add(&mut self, other: &Window) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.overruns += other.overruns;
+        self.min = cmp::min(self.min, other.min);
+        self.max = cmp::max(self.max, other.max);
+        for (total, n) in self.buckets.iter_mut().zip(&other.buckets) {
+            *total += n;
+        }
+    }
+
+    fn min(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    fn average(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    fn percentile(&self, p: f64) -> u64 {
+        let target = (self.count as f64 * p / 100.0).ceil() as u64;
+        let mut seen = 0;
+
+        for (index, n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= cmp::max(target, 1) {
+                return cmp::min(bucket_value(index), self.max);
+            }
+        }
+
+        0
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "samples": self.count,
+            "min_ns": self.min(),
+            "avg_ns": self.average(),
+            "max_ns": self.max,
+            "p99_ns": self.percentile(99.0),
+            "overruns": self.overruns,
+        })
+    }
+
+    fn format(&self, unit: Unit) -> String {
+        format!(
+            "samples {} min {} avg {} max {} p99 {} overruns {}",
+            self.count,
+            unit.format(self.min() as f64),
+            unit.format(self.average()),
+            unit.format(self.max as f64),
+            unit.format(self.percentile(99.0) as f64),
+            self.overruns
+        )
+    }
+}
+
+/// Low priority thread printing interim statistics of all the timer threads.
+pub struct Reporter {
+    done: Arc<AtomicBool>,
+    thread_handle: thread::JoinHandle<()>,
+}
+
+impl Reporter {
+    pub fn new(stats: Vec<Arc<LiveStats>>, interval: Duration, output: Output, unit: Unit) -> Self {
+        let done = Arc::new(AtomicBool::new(false));
+        let reporter_done = done.clone();
+
+        let thread_handle = thread::spawn(move || {
+            set_self_priority(Which::Process, 19).unwrap();
+
+            let start = Instant::now();
+            let mut next = start + interval;
+            let mut previous: Vec<Window> = stats.iter().map(|_| Window::new()).collect();
+            let mut total = Window::new();
+
+            loop {
+                if reporter_done.load(Ordering::Acquire) {
+                    break;
+                }
+
+                let now = Instant::now();
+                if now < next {
+                    thread::park_timeout(next - now);
+                    continue;
+                }
+                next += interval;
+
+                let mut window = Window::new();
+                for (live, prev) in stats.iter().zip(previous.iter_mut()) {
+                    let count = live.count.load(Ordering::Relaxed);
+                    let sum = live.sum.load(Ordering::Relaxed);
+                    let overruns = live.overruns.load(Ordering::Relaxed);
+
+                    window.count += count - prev.count;
+                    window.sum += sum - prev.sum;
+                    window.overruns += overruns - prev.overruns;
+                    window.min = cmp::min(
+                        window.min,
+                        live.interval_min.swap(u64::MAX, Ordering::Relaxed),
+                    );
+                    window.max = cmp::max(window.max, live.interval_max.swap(0, Ordering::Relaxed));
+
+                    for (i, bucket) in live.buckets.iter().enumerate() {
+                        let n = bucket.load(Ordering::Relaxed);
+                        window.buckets[i] += n - prev.buckets[i];
+                        prev.buckets[i] = n;
+                    }
+
+                    prev.count = count;
+                    prev.sum = sum;
+                    prev.overruns = overruns;
+                }
+                total.add(&window);
+
+                match output {
+                    Output::Text => eprintln!(
+                        "[{:.1}s] interval: {} | total: {}",
+                        start.elapsed().as_secs_f64(),
+                        window.format(unit),
+                        total.format(unit)
+                    ),
+                    Output::Json => eprintln!(
+                        "{}",
+                        json!({
+                            "time_ns": unix_time_ns(SystemTime::now()),
+                            "elapsed_ns": start.elapsed().as_nanos() as u64,
+                            "interval": window.to_json(),
+                            "total": total.to_json(),
+                        })
+                    ),
+                }
+            }
+        });
+
+        Reporter {
+            done,
+            thread_handle,
+        }
+    }
+
+    pub fn stop(self) {
+        self.done.store(true, Ordering::Release);
+        self.thread_handle.thread().unpark();
+        self.thread_handle.join().unwrap();
+    }
+}
@@ -1,204 +1,630 @@
+mod cpus;
+mod cpustat;
+mod deadline;
+mod live;
+mod policy;
+mod report;
+mod samplelog;
+mod stats;
+mod task;
+mod threshold;
+mod timer;
+mod trace;
+mod worker;
+mod workload;
+
 use affinity::*;
 use clap::Parser;
-use core::mem;
-use duration_str;
-use errno::errno;
-use gettid::gettid;
-use libc::c_void;
-use scheduler::{set_self_policy, Policy};
+use cpus::{format_cpulist, parse_allowed_cpulist, usable_cpus};
+use cpustat::Sampler;
+use deadline::{count_runtime_overruns, DeadlineConfig};
+use live::{LiveStats, Reporter};
+use policy::parse_policies;
+use report::{machine_info, open_output, unix_time_ns, Output, Results};
+use samplelog::SampleWriter;
+use serde::Serialize;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use signal_hook::iterator::Signals;
-use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
-use std::time::{Duration, Instant};
-use std::{cmp, error::Error, ops::Drop, ptr, sync::mpsc, thread};
-use volatile::Volatile;
-
-struct TimerId(*mut c_void);
+use stats::{write_reports, TimerLabel, Unit};
+use std::fmt::Display;
+use std::io::Write;
+use std::process::ExitCode;
+use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc, Weak};
+use std::time::{Duration, Instant, SystemTime};
+use std::{error::Error, thread};
+use threshold::Thresholds;
+use timer::{Clock, Measure, Mode, TimerConfig, TimerOutputs, TimerThread};
+use trace::{exit_restoring, wait_for_restore, Tracer};
+use worker::{run_worker_threads, write_worker_reports, DutyCycleConfig, WorkerConfig};
+use workload::{WorkloadConfig, WorkloadKind};
+
+/// Requests a graceful stop on the first termination signal, so the final
+/// report is still printed, and exits right away on the second one, only
+/// putting the tracing settings back.
+fn handle_termination_signals(
+    quit: Arc<AtomicBool>,
+    main_thread: thread::Thread,
+    tracer: Option<Weak<Tracer>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut signals = Signals::new([SIGINT, SIGTERM, SIGHUP])?;
+
+    thread::spawn(move || {
+        let mut pending = signals.forever();
+
+        if let Some(sig) = pending.next() {
+            eprintln!("Received signal {}, stopping...", sig);
+            quit.store(true, Ordering::Release);
+            main_thread.unpark();
+        }
 
-impl Drop for TimerId {
-    fn drop(&mut self) {
-        if !self.0.is_null() {
-            unsafe { libc::timer_delete(self.0) };
+        if let Some(sig) = pending.next() {
+            eprintln!("Received signal {} again, exiting immediately", sig);
+            exit_restoring(tracer.as_ref(), 128 + sig);
         }
-    }
+    });
+
+    Ok(())
 }
 
-struct Timer(TimerId);
+#[derive(Parser, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+#[clap(name = "stress-lb")]
+#[clap(author = "Wander Lairson Costa <wcosta@redhat.com>")]
+#[clap(about = "Stress the kernel scheduler load-balancer for worst case scenario")]
+#[clap(long_about = None)]
+#[clap(
+    after_help = "Exit status: 0 on success, 1 if a threshold was exceeded, 2 on command line \
+                  errors, 3 if the test could not be set up and 4 on errors during the run."
+)]
+struct Args {
+    #[clap(short, long, default_value_t = 3)]
+    threads_per_core: usize,
 
-impl Timer {
-    pub fn new(thread_id: i32, dur: &Duration) -> Result<Self, Box<dyn Error>> {
-        let mut timerid = TimerId(ptr::null_mut());
-        let mut sigev: libc::sigevent = unsafe { mem::zeroed() };
+    #[clap(short, long)]
+    duration: Option<String>,
 
-        sigev.sigev_notify = libc::SIGEV_THREAD_ID;
-        sigev.sigev_signo = libc::SIGALRM;
-        sigev.sigev_notify_thread_id = thread_id;
+    #[clap(short, long)]
+    interval: Option<String>,
 
-        let mut ret;
-        unsafe {
-            ret = libc::timer_create(
-                libc::CLOCK_MONOTONIC,
-                &mut sigev as *mut libc::sigevent,
-                &mut timerid.0 as *mut *mut libc::c_void,
-            );
-        }
+    #[clap(short, long, default_value_t = 1)]
+    priority: u32,
 
-        if ret < 0 {
-            return Err(Box::new(errno()));
-        }
+    /// Scheduling policies of the worker threads, taken in turn: other[:nice],
+    /// batch[:nice], idle, fifo:prio or rr:prio, with real time priorities
+    /// below the timer one. E.g. "other,batch:5,fifo:1" runs a third of the
+    /// workers with each
+    #[clap(long, default_value = "other")]
+    worker_policy: String,
+
+    /// Load the worker threads put on their CPUs
+    #[clap(short, long, arg_enum, default_value = "alu")]
+    workload: WorkloadKind,
+
+    /// Working set of each worker thread for the memory and cache
+    /// workloads, in bytes with an optional K, M or G suffix
+    #[clap(long, default_value = "16M")]
+    working_set: String,
+
+    /// Threads passing a token around in the pipe, eventfd and futex
+    /// workloads
+    #[clap(long, default_value_t = 2)]
+    group_size: usize,
+
+    /// Size of the messages of the pipe workload, in bytes with an optional
+    /// K, M or G suffix
+    #[clap(long, default_value = "1")]
+    message_size: String,
+
+    /// Make the workers sleep this long after every run period, so they
+    /// keep waking up
+    #[clap(long, requires = "run-time")]
+    sleep_time: Option<String>,
+
+    /// How long the workers run before sleeping
+    #[clap(long, requires = "sleep-time")]
+    run_time: Option<String>,
+
+    /// Randomly stretch or shrink every run and sleep period by up to this
+    /// fraction, between 0 and 1
+    #[clap(long, default_value_t = 0.0)]
+    duty_jitter: f64,
+
+    /// Run the timer threads under SCHED_DEADLINE with this runtime,
+    /// instead of SCHED_FIFO. The kernel only admits deadline threads
+    /// allowed on every CPU, so they are not pinned and --timer-cpus only
+    /// sets how many there are
+    #[clap(long)]
+    dl_runtime: Option<String>,
+
+    /// SCHED_DEADLINE relative deadline [default: the period]
+    #[clap(long, requires = "dl-runtime")]
+    dl_deadline: Option<String>,
+
+    /// SCHED_DEADLINE period [default: the timer interval]
+    #[clap(long, requires = "dl-runtime")]
+    dl_period: Option<String>,
+
+    /// Mechanism used to wait for the timer expiration
+    #[clap(long, arg_enum, default_value = "signal")]
+    mode: Mode,
+
+    /// How latency is measured
+    #[clap(short, long, arg_enum, default_value = "relative")]
+    measure: Measure,
+
+    /// CPUs to run a timer thread on, in cpulist format (e.g. 0-3,8), or
+    /// "all" [default: the first usable CPU]
+    #[clap(long)]
+    timer_cpus: Option<String>,
+
+    /// CPUs the worker threads run on, in cpulist format (e.g. 0-3,8), or
+    /// "all" [default: all usable CPUs but the timer ones]
+    #[clap(long)]
+    worker_cpus: Option<String>,
+
+    /// Clock source for the timer and the measurement
+    #[clap(short, long, arg_enum, default_value = "monotonic")]
+    clock: Clock,
+
+    /// Unit used to report latencies
+    #[clap(short, long, arg_enum, default_value = "us")]
+    unit: Unit,
+
+    /// Format of the final report
+    #[clap(short, long, arg_enum, default_value = "text")]
+    output: Output,
+
+    /// File to write the final report to, instead of stdout
+    #[clap(long)]
+    output_file: Option<String>,
+
+    /// CSV file to log every single latency sample to
+    #[clap(long)]
+    sample_log: Option<String>,
+
+    /// Print interim statistics to stderr at this interval, as text or
+    /// JSON lines depending on --output
+    #[clap(long)]
+    report_interval: Option<String>,
+
+    /// Sample the utilization and runqueue statistics of every usable CPU
+    /// at this interval
+    #[clap(long)]
+    cpu_stats_interval: Option<String>,
+
+    /// Fail if any timer thread sees a latency above this
+    #[clap(long)]
+    max_latency: Option<String>,
+
+    /// Fail if the P99 latency of any timer thread is above this
+    #[clap(long)]
+    max_p99: Option<String>,
+
+    /// Fail if the timer threads miss more periods than this
+    #[clap(long)]
+    max_overruns: Option<u64>,
+
+    /// Stop ftrace tracing on the first latency above this
+    #[clap(short, long)]
+    breaktrace: Option<String>,
 
-        let mut tmspec: libc::itimerspec = unsafe { mem::zeroed() };
-        tmspec.it_interval.tv_sec = dur.as_secs() as i64;
-        tmspec.it_interval.tv_nsec = dur.subsec_nanos() as i64;
-        tmspec.it_value.tv_sec = dur.as_secs() as i64;
-        tmspec.it_value.tv_nsec = dur.subsec_nanos() as i64;
+    /// Enable the sched_switch, sched_migrate_task and sched_wakeup events
+    /// and turn tracing on for the run
+    #[clap(long)]
+    trace_events: bool,
 
-        unsafe {
-            ret = libc::timer_settime(timerid.0, 0, &tmspec, ptr::null_mut());
-        }
+    /// Mark every latency above this in the ftrace buffer
+    #[clap(long)]
+    trace_threshold: Option<String>,
 
-        if ret < 0 {
-            Err(Box::new(errno()))
-        } else {
-            Ok(Timer(timerid))
-        }
-    }
+    /// tracefs mount point [default: /sys/kernel/tracing or
+    /// /sys/kernel/debug/tracing]
+    #[clap(long)]
+    tracefs: Option<String>,
 }
 
-struct TimerThread {
-    timer: Option<Timer>,
-    thread_handle: Option<thread::JoinHandle<()>>,
+/// Timer interval used without --interval.
+const DEFAULT_INTERVAL: &str = "1ms";
+
+/// Exit codes, so stress-lb can be used as a pass/fail gate. Command line
+/// errors exit with 2, like the ones clap catches.
+const EXIT_THRESHOLD: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_SETUP: u8 = 3;
+const EXIT_RUNTIME: u8 = 4;
+
+enum Failure {
+    /// An option has an invalid value
+    Usage(String),
+    /// The test could not be started, e.g. no permission to use SCHED_FIFO
+    Setup(String),
+    /// Something went wrong while the test was running
+    Runtime(String),
 }
 
-impl TimerThread {
-    pub fn new(
-        interval: &Duration,
-        priority: u32,
-        quit: Arc<AtomicBool>,
-    ) -> Result<Self, Box<dyn Error>> {
-        let mut signals = Signals::new(&[signal_hook::consts::SIGALRM])?;
-
-        let (tx, rx) = mpsc::channel();
-        let mut checkpoint = Instant::now();
-        let delay = interval.clone();
-
-        let handle = thread::spawn(move || {
-            tx.send(gettid()).unwrap();
-
-            let core_mask: Vec<usize> = (0..1).collect();
-            set_thread_affinity(&core_mask).unwrap();
-            set_self_policy(Policy::Fifo, priority as i32).unwrap();
-
-            let mut count: u128 = 0;
-            let mut sum: u128 = 0;
-            let mut max_latency: usize = 0;
-
-            for _ in signals.forever() {
-                if quit.load(Ordering::Acquire) {
-                    println!("Average latency = {}us", sum / count);
-                    println!("Maximum latency = {}us", max_latency);
-                    return;
-                }
-
-                let diff = Instant::now().duration_since(checkpoint);
-                let latency = if diff > delay {
-                    diff - delay
-                } else {
-                    Duration::new(0, 0)
-                };
-
-                count += 1;
-                sum += latency.as_micros();
-                max_latency = cmp::max(max_latency, latency.as_micros() as usize);
-
-                checkpoint = Instant::now();
-            }
-        });
+fn usage_error<E: Display>(e: E) -> Failure {
+    Failure::Usage(e.to_string())
+}
 
-        let thread_id = rx.recv()?;
-        let timer = Timer::new(thread_id as i32, interval)?;
+fn setup_error<E: Display>(e: E) -> Failure {
+    Failure::Setup(e.to_string())
+}
 
-        Ok(TimerThread {
-            timer: Some(timer),
-            thread_handle: Some(handle),
-        })
-    }
+fn runtime_error<E: Display>(e: E) -> Failure {
+    Failure::Runtime(e.to_string())
+}
 
-    pub fn join(&mut self) -> thread::Result<()> {
-        mem::replace(&mut self.thread_handle, None).unwrap().join()
-    }
+fn parse_duration(d: &str) -> Result<Duration, Failure> {
+    duration_str::parse(d).map_err(|e| usage_error(format!("invalid duration \"{}\": {}", d, e)))
 }
 
-fn run_worker_threads(
-    quit: Arc<AtomicBool>,
-    threads_per_core: usize,
-) -> Vec<thread::JoinHandle<()>> {
-    let ncpus = get_core_num();
-    let num_threads = (ncpus - 1) * threads_per_core;
-
-    println!("This machine has {} CPUs", ncpus);
-    println!("Starting {} worker threads...", num_threads);
-
-    (0..num_threads)
-        .map(move |_| {
-            let myquit = quit.clone();
-            thread::spawn(move || {
-                let core_mask: Vec<usize> = (1..ncpus).collect();
-                set_thread_affinity(&core_mask).unwrap();
-
-                let mut dummy: u64 = 0;
-                let mut volatile_dummy = Volatile::new(&mut dummy);
-
-                while !myquit.load(Ordering::Acquire) {
-                    // just useless computation
-                    volatile_dummy.write(volatile_dummy.read().wrapping_add(1));
-                }
-            })
-        })
-        .collect()
+/// Parses a size in bytes, with an optional K, M or G (binary) suffix.
+fn parse_size(s: &str) -> Result<usize, Failure> {
+    let (number, shift) = match s.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&s[..i], 10),
+        Some((i, 'M' | 'm')) => (&s[..i], 20),
+        Some((i, 'G' | 'g')) => (&s[..i], 30),
+        _ => (s, 0),
+    };
+
+    number
+        .parse::<usize>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| usage_error(format!("invalid size \"{}\"", s)))
 }
 
-#[derive(Parser, Debug)]
-#[clap(name = "stress-lb")]
-#[clap(author = "Wander Lairson Costa <wcosta@redhat.com>")]
-#[clap(about = "Stress the kernel scheduler load-balancer for worst case scenario")]
-#[clap(long_about = None)]
-struct Args {
-    #[clap(short, long, default_value_t = 3)]
-    threads_per_core: usize,
+/// Runs the test and returns whether it stayed within the thresholds.
+fn run(args: &Args) -> Result<bool, Failure> {
+    let interval_arg = args.interval.as_deref().unwrap_or(DEFAULT_INTERVAL);
+    let interval = parse_duration(interval_arg)?;
+    if interval.is_zero() {
+        return Err(usage_error("the timer interval must not be zero"));
+    }
 
-    #[clap(short, long)]
-    duration: Option<String>,
+    // The expected expiration times come from the timer clock, so they are
+    // only comparable with a clock the timer can run on
+    if args.measure == Measure::Absolute && !args.clock.arms_timers() {
+        return Err(usage_error(
+            "the monotonic-raw clock only supports relative measurement",
+        ));
+    }
+    if args.mode == Mode::Timerfd && args.clock == Clock::Tai {
+        return Err(usage_error(
+            "timerfd does not support the tai clock, use --mode signal or nanosleep",
+        ));
+    }
 
-    #[clap(short, long)]
-    interval: Option<String>,
+    let thresholds = Thresholds {
+        max_latency: args
+            .max_latency
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?
+            .map(|d| d.as_nanos() as u64),
+        max_p99: args
+            .max_p99
+            .as_deref()
+            .map(parse_duration)
+            .transpose()?
+            .map(|d| d.as_nanos() as u64),
+        max_overruns: args.max_overruns,
+    };
 
-    #[clap(short, long, default_value_t = 1)]
-    priority: u32,
-}
+    let deadline = match &args.dl_runtime {
+        Some(runtime) => {
+            let period = match &args.dl_period {
+                Some(period) => parse_duration(period)?,
+                None => interval,
+            };
+            let deadline = DeadlineConfig {
+                runtime: parse_duration(runtime)?,
+                deadline: match &args.dl_deadline {
+                    Some(deadline) => parse_duration(deadline)?,
+                    None => period,
+                },
+                period,
+            };
+            deadline.validate().map_err(usage_error)?;
+            Some(deadline)
+        }
+        None => None,
+    };
 
-fn main() {
-    let args = Args::parse();
+    let ncpus = get_core_num();
+    let usable = usable_cpus().map_err(setup_error)?;
+    eprintln!(
+        "This machine has {} CPUs, {} usable ({})",
+        ncpus,
+        usable.len(),
+        format_cpulist(&usable)
+    );
+
+    let timer_cpus = match &args.timer_cpus {
+        Some(list) => parse_allowed_cpulist(list, &usable).map_err(usage_error)?,
+        None => vec![usable[0]],
+    };
+    let worker_cpus = match &args.worker_cpus {
+        Some(list) => parse_allowed_cpulist(list, &usable).map_err(usage_error)?,
+        None => usable
+            .iter()
+            .copied()
+            .filter(|cpu| !timer_cpus.contains(cpu))
+            .collect(),
+    };
+    let worker_cpus = if worker_cpus.is_empty() {
+        eprintln!("Warning: no CPUs left for the worker threads, sharing the timer ones");
+        timer_cpus.clone()
+    } else {
+        worker_cpus
+    };
 
-    let interval =
-        duration_str::parse(&args.interval.or(Some("1ms".to_string())).unwrap()).unwrap();
+    // Report the settings actually used, defaults and fallbacks included
+    let mut config = serde_json::to_value(args).map_err(setup_error)?;
+    config["interval"] = interval_arg.into();
+    config["timer-cpus"] = format_cpulist(&timer_cpus).into();
+    config["worker-cpus"] = format_cpulist(&worker_cpus).into();
+
+    if !(1..=99).contains(&args.priority) {
+        return Err(usage_error("the timer priority must be between 1 and 99"));
+    }
+    if args.group_size == 0 {
+        return Err(usage_error("the group size must be at least 1"));
+    }
+    if !(0.0..=1.0).contains(&args.duty_jitter) {
+        return Err(usage_error("--duty-jitter must be between 0 and 1"));
+    }
+    let duty_cycle = match (&args.run_time, &args.sleep_time) {
+        (Some(run), Some(sleep)) => Some(DutyCycleConfig {
+            run: parse_duration(run)?,
+            sleep: parse_duration(sleep)?,
+            jitter: args.duty_jitter,
+        }),
+        _ => None,
+    };
+
+    let policies = parse_policies(&args.worker_policy).map_err(usage_error)?;
+    if let Some(policy) = policies
+        .iter()
+        .find(|policy| policy.rt_priority().is_some_and(|p| p >= args.priority))
+    {
+        return Err(usage_error(format!(
+            "worker policy {} must have a lower priority than the timer threads ({})",
+            policy, args.priority
+        )));
+    }
+
+    let worker_config = WorkerConfig {
+        threads_per_core: args.threads_per_core,
+        workload: WorkloadConfig {
+            kind: args.workload,
+            working_set: parse_size(&args.working_set)?,
+            group_size: args.group_size,
+            message_size: parse_size(&args.message_size)?,
+        },
+        policies,
+        duty_cycle,
+    };
+
+    let dur = args.duration.as_deref().map(parse_duration).transpose()?;
+    let report_interval = args
+        .report_interval
+        .as_deref()
+        .map(parse_duration)
+        .transpose()?;
+    let cpu_stats_interval = args
+        .cpu_stats_interval
+        .as_deref()
+        .map(parse_duration)
+        .transpose()?;
+    let breaktrace = args.breaktrace.as_deref().map(parse_duration).transpose()?;
+    let trace_threshold = args
+        .trace_threshold
+        .as_deref()
+        .map(parse_duration)
+        .transpose()?;
+    let mut out = open_output(&args.output_file).map_err(setup_error)?;
+
+    // Test phases are marked in the trace whenever any tracing option is used
+    let tracer = if breaktrace.is_some() || trace_threshold.is_some() || args.trace_events {
+        let tracer =
+            Tracer::new(args.tracefs.as_deref(), args.trace_events).map_err(setup_error)?;
+        Some(Arc::new(tracer))
+    } else {
+        None
+    };
 
     let quit = Arc::new(AtomicBool::new(false));
+    handle_termination_signals(
+        quit.clone(),
+        thread::current(),
+        tracer.as_ref().map(Arc::downgrade),
+    )
+    .map_err(setup_error)?;
+
+    let workers =
+        run_worker_threads(worker_cpus, &worker_config, quit.clone()).map_err(setup_error)?;
+    if let Some(tracer) = &tracer {
+        tracer.phase("workers started").map_err(runtime_error)?;
+    }
 
-    let threads = run_worker_threads(quit.clone(), args.threads_per_core);
+    let sampler = cpu_stats_interval.map(|interval| Sampler::new(usable.clone(), interval));
+
+    let start_time = SystemTime::now();
+    let timer_config = TimerConfig {
+        interval,
+        priority: args.priority,
+        mode: args.mode,
+        measure: args.measure,
+        clock: args.clock,
+        breaktrace: breaktrace.map(|d| d.as_nanos() as u64),
+        trace_threshold: trace_threshold.map(|d| d.as_nanos() as u64),
+        deadline,
+    };
+    let runtime_overruns = match deadline {
+        Some(_) => Some(count_runtime_overruns().map_err(setup_error)?),
+        None => None,
+    };
 
-    println!("Starting the timer thread...");
-    let mut timer = TimerThread::new(&interval, args.priority, quit.clone()).unwrap();
+    eprintln!("Starting {} timer threads...", timer_cpus.len());
+    let sample_writer = match &args.sample_log {
+        Some(path) => {
+            let epoch_offset = unix_time_ns(SystemTime::now()) as i64 - args.clock.now() as i64;
+            let id_column = match deadline {
+                Some(_) => "timer",
+                None => "cpu",
+            };
+            Some(SampleWriter::new(path, epoch_offset, id_column).map_err(setup_error)?)
+        }
+        None => None,
+    };
 
-    let dur = match args.duration {
-        Some(d) => duration_str::parse(&d).unwrap(),
-        None => Duration::MAX,
+    let live_stats: Vec<Arc<LiveStats>> = match report_interval {
+        Some(_) => timer_cpus
+            .iter()
+            .map(|_| Arc::new(LiveStats::new()))
+            .collect(),
+        None => Vec::new(),
     };
 
-    thread::sleep(dur);
+    let mut timers = Vec::new();
+    for (i, &cpu) in timer_cpus.iter().enumerate() {
+        // Deadline threads are not pinned, the CPU would not tell the truth
+        let label = match deadline {
+            Some(_) => TimerLabel::Index(i),
+            None => TimerLabel::Cpu(cpu),
+        };
+        let outputs = TimerOutputs {
+            sample_log: sample_writer.as_ref().map(SampleWriter::log),
+            live: live_stats.get(i).cloned(),
+            tracer: tracer.clone(),
+        };
+        let timer = TimerThread::new(label, &timer_config, outputs, quit.clone())
+            .map_err(|e| setup_error(format!("timer thread ({}): {}", label, e)))?;
+        timers.push(timer);
+    }
+
+    let reporter =
+        report_interval.map(|interval| Reporter::new(live_stats, interval, args.output, args.unit));
+
+    // Run until the duration expires or a termination signal arrives
+    let deadline = dur.map(|d| Instant::now() + d);
+    while !quit.load(Ordering::Acquire) {
+        match deadline {
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) => thread::park_timeout(left),
+                None => break,
+            },
+            None => thread::park(),
+        }
+    }
+
     quit.store(true, Ordering::Release);
-    timer.join().unwrap();
+    if let Some(tracer) = &tracer {
+        tracer.phase("quit requested").map_err(runtime_error)?;
+    }
+
+    let reports = timers
+        .iter_mut()
+        .map(|t| t.join())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| runtime_error("timer thread panicked"))?;
+    let end_time = SystemTime::now();
+
+    if let Some(reporter) = reporter {
+        reporter.stop();
+    }
+    let cpu_stats = sampler.map(Sampler::stop);
+
+    if let Some(writer) = sample_writer {
+        let dropped = writer.finish().map_err(runtime_error)?;
+        if dropped > 0 {
+            eprintln!(
+                "Dropped {} samples, the sample log could not keep up",
+                dropped
+            );
+        }
+    }
 
-    for t in threads {
-        t.join().unwrap();
+    if let Some(tracer) = &tracer {
+        if let Some(trigger) = tracer.trigger() {
+            eprintln!(
+                "Tracing stopped: {} sample {} latency {} above the breaktrace threshold",
+                trigger.timer,
+                trigger.seq,
+                args.unit.format(trigger.latency as f64)
+            );
+        }
+    }
+
+    let worker_reports = workers
+        .join()
+        .map_err(|_| runtime_error("worker thread panicked"))?;
+
+    let violations = thresholds.check(&reports, args.unit);
+    let runtime_overruns = runtime_overruns.map(|count| count.load(Ordering::Relaxed));
+
+    match args.output {
+        Output::Text => {
+            write_reports(&mut out, &reports, args.unit).map_err(runtime_error)?;
+            write_worker_reports(&mut out, &worker_reports).map_err(runtime_error)?;
+            if let Some(overruns) = runtime_overruns {
+                writeln!(
+                    out,
+                    "Deadline runtime overruns = {} (all timer threads together)",
+                    overruns
+                )
+                .map_err(runtime_error)?;
+            }
+            if let Some(cpu_stats) = &cpu_stats {
+                cpu_stats
+                    .write(&mut out, args.unit)
+                    .map_err(runtime_error)?;
+            }
+            for violation in &violations {
+                writeln!(out, "FAIL: {}", violation).map_err(runtime_error)?;
+            }
+        }
+        Output::Json => {
+            let machine = machine_info(ncpus, &usable);
+            let results = Results {
+                start: start_time,
+                end: end_time,
+                timers: &reports,
+                workers: &worker_reports,
+                cpu_stats: cpu_stats.as_ref(),
+                runtime_overruns,
+                violations: &violations,
+            };
+            let doc = report::to_json(&config, machine, &results);
+            serde_json::to_writer_pretty(&mut out, &doc).map_err(runtime_error)?;
+            writeln!(out).map_err(runtime_error)?;
+        }
+    }
+
+    Ok(violations.is_empty())
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse();
+
+    let result = run(&args);
+    wait_for_restore();
+
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::from(EXIT_THRESHOLD),
+        Err(Failure::Usage(e)) => {
+            eprintln!("error: {}", e);
+            ExitCode::from(EXIT_USAGE)
+        }
+        Err(Failure::Setup(e)) => {
+            eprintln!("Setup failed: {}", e);
+            ExitCode::from(EXIT_SETUP)
+        }
+        Err(Failure::Runtime(e)) => {
+            eprintln!("Error: {}", e);
+            ExitCode::from(EXIT_RUNTIME)
+        }
     }
 }
@@ -0,0 +1,107 @@
+use errno::errno;
+use scheduler::{set_self_policy, set_self_priority, Policy, Which};
+use std::error::Error;
+use std::fmt;
+
+/// Scheduling policy of a worker thread.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WorkerPolicy {
+    Other { nice: i32 },
+    Batch { nice: i32 },
+    Idle,
+    Fifo { priority: u32 },
+    Rr { priority: u32 },
+}
+
+impl WorkerPolicy {
+    /// Parses "policy[:value]", where the value is the nice level of other
+    /// and batch, and the priority of fifo and rr.
+    pub fn parse(spec: &str) -> Result<Self, Box<dyn Error>> {
+        let (name, value) = match spec.split_once(':') {
+            Some((name, value)) => (name, Some(value)),
+            None => (spec, None),
+        };
+
+        let nice = || -> Result<i32, Box<dyn Error>> {
+            let nice = value.map_or(Ok(0), str::parse)?;
+            if !(-20..=19).contains(&nice) {
+                return Err(format!("nice value {} out of range", nice).into());
+            }
+            Ok(nice)
+        };
+        let priority = || -> Result<u32, Box<dyn Error>> {
+            let priority = value
+                .ok_or_else(|| format!("{} needs a priority, e.g. {}:1", name, name))?
+                .parse()?;
+            if !(1..=99).contains(&priority) {
+                return Err(format!("priority {} out of range", priority).into());
+            }
+            Ok(priority)
+        };
+
+        let policy = match name {
+            "other" => WorkerPolicy::Other { nice: nice()? },
+            "batch" => WorkerPolicy::Batch { nice: nice()? },
+            "idle" if value.is_none() => WorkerPolicy::Idle,
+            "fifo" => WorkerPolicy::Fifo {
+                priority: priority()?,
+            },
+            "rr" => WorkerPolicy::Rr {
+                priority: priority()?,
+            },
+            _ => return Err(format!("invalid worker policy \"{}\"", spec).into()),
+        };
+
+        Ok(policy)
+    }
+
+    /// Real time priority, for the policies that have one.
+    pub fn rt_priority(&self) -> Option<u32> {
+        match *self {
+            WorkerPolicy::Fifo { priority } | WorkerPolicy::Rr { priority } => Some(priority),
+            _ => None,
+        }
+    }
+
+    /// Applies the policy to the calling thread.
+    pub fn apply(&self) -> Result<(), Box<dyn Error>> {
+        let (policy, priority, nice) = match *self {
+            WorkerPolicy::Other { nice } => (Policy::Other, 0, Some(nice)),
+            WorkerPolicy::Batch { nice } => (Policy::Batch, 0, Some(nice)),
+            WorkerPolicy::Idle => (Policy::Idle, 0, None),
+            WorkerPolicy::Fifo { priority } => (Policy::Fifo, priority as i32, None),
+            WorkerPolicy::Rr { priority } => (Policy::RoundRobin, priority as i32, None),
+        };
+
+        set_self_policy(policy, priority)
+            .map_err(|_| format!("cannot set worker policy {}: {}", self, errno()))?;
+
+        // On Linux the nice value is per thread
+        if let Some(nice) = nice {
+            set_self_priority(Which::Process, nice)
+                .map_err(|_| format!("cannot set nice value {}: {}", nice, errno()))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for WorkerPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WorkerPolicy::Other { nice } => write!(f, "other:{}", nice),
+            WorkerPolicy::Batch { nice } => write!(f, "batch:{}", nice),
+            WorkerPolicy::Idle => write!(f, "idle"),
+            WorkerPolicy::Fifo { priority } => write!(f, "fifo:{}", priority),
+            WorkerPolicy::Rr { priority } => write!(f, "rr:{}", priority),
+        }
+    }
+}
+
+/// Parses a comma separated list of policies. Worker threads take them in
+/// turn, so "other,batch,idle" runs a third of the workers with each.
+pub fn parse_policies(list: &str) -> Result<Vec<WorkerPolicy>, Box<dyn Error>> {
+    list.split(',')
+        .map(|spec| WorkerPolicy::parse(spec.trim()))
+        .collect()
+}
@@ -0,0 +1,216 @@
+//! Machine readable results.
+//!
+//! With `--output json` the final report is a single JSON document with
+//! the following layout. Every duration is in nanoseconds and every
+//! timestamp in nanoseconds since the Unix epoch. Fields are only ever added
+//! within a schema version; removing or changing the meaning of one bumps
+//! `schema_version`.
+//!
+//! ```text
+//! {
+//!   "schema_version": 1,
+//!   "start_time_ns": u64,
+//!   "end_time_ns": u64,
+//!   "config": { command line options, by their long name, with the
+//!               interval and the CPU lists actually used },
+//!   "machine": {
+//!     "cpus": number of CPUs in the machine,
+//!     "usable_cpus": [ CPUs allowed by the affinity mask and cpuset ],
+//!     "hostname": string,
+//!     "kernel": { "sysname", "release", "version", "machine" }
+//!   },
+//!   "timers": [ timer report, one per timer thread ],
+//!   "aggregate": timer report of all the timer threads together,
+//!   "workers": {
+//!     "threads": [ worker report, one per worker thread ],
+//!     "cpus": [ { "cpu", "iterations_per_sec" } by all the workers on it ],
+//!     "policies": [ { "policy", "workers", "iterations_per_sec" } average
+//!                   per worker of each --worker-policy ],
+//!     "fairness": { "jain_index", "min_max_ratio" } of the worker throughput,
+//!     "workload": { counter: count } summed over all the workers,
+//!     "migrations": { "observed", "kernel" } summed over all the workers
+//!   },
+//!   "cpu_stats": CPU statistics, null without --cpu-stats-interval,
+//!   "deadline": {
+//!     "runtime_overruns": times any timer thread ran past its runtime; the
+//!                         kernel signals them to the whole process, so
+//!                         there is no per thread count
+//!   }, or null without --dl-runtime,
+//!   "thresholds": {
+//!     "passed": bool,
+//!     "violations": [ description of every exceeded threshold ]
+//!   }
+//! }
+//! ```
+//!
+//! A timer report is:
+//!
+//! ```text
+//! {
+//!   "cpu": CPU the thread was pinned to, null for the aggregate and under
+//!          SCHED_DEADLINE, where the threads are not pinned,
+//!   "timer": index of the thread under SCHED_DEADLINE, null otherwise,
+//!   "latency": {
+//!     "samples", "min_ns", "avg_ns", "max_ns", "stddev_ns",
+//!     "percentiles_ns": { "p50", "p90", "p99", "p99.9", "p99.99" },
+//!     "histogram": [ { "value_ns", "count" } ]
+//!   },
+//!   "jitter": { "samples", "min_ns", "avg_ns", "max_ns" }, or null in
+//!             relative measurement mode,
+//!   "overruns": { "total", "max_streak" },
+//!   "deadline_misses": periods that ended past their deadline, or null
+//!                      without --dl-runtime,
+//!   "task": scheduler accounting of the thread during the run, or null
+//! }
+//! ```
+//!
+//! A worker report is:
+//!
+//! ```text
+//! {
+//!   "id": u64,
+//!   "policy": scheduling policy, e.g. "other:0" or "fifo:1",
+//!   "iterations": iterations of the busy loop,
+//!   "elapsed_ns": u64,
+//!   "iterations_per_sec": f64,
+//!   "migrations": {
+//!     "observed": CPU changes the worker saw by polling sched_getcpu(),
+//!     "kernel": se.nr_migrations from /proc, or null if not available
+//!   },
+//!   "task": scheduler accounting of the thread during the run, or null,
+//!   "workload": { counter: count } of work specific to --workload, e.g.
+//!               "bytes" copied by the memory workload,
+//!   "duty_cycle": { "requested" fraction of the time the worker runs,
+//!                   "achieved" CPU time of the thread over its elapsed time,
+//!                   or null if /proc does not have it }, or null without
+//!                 --sleep-time
+//! }
+//! ```
+//!
+//! Scheduler accounting comes from /proc/self/task/<tid> and is:
+//!
+//! ```text
+//! {
+//!   "voluntary_switches", "involuntary_switches",
+//!   "runtime_ns": time on the CPU, or null if not available,
+//!   "wait_ns": time waiting on a runqueue, or null if not available
+//! }
+//! ```
+//!
+//! CPU statistics are sampled from /proc/stat and /proc/schedstat for
+//! every usable CPU:
+//!
+//! ```text
+//! {
+//!   "interval_ns": u64,
+//!   "cpus": [ {
+//!     "cpu", "busy_pct": average utilization,
+//!     "run_delay_ns", "lb_count", "lb_failed", "lb_gained": totals over the
+//!       run, null if /proc/schedstat is not available
+//!   } ],
+//!   "imbalance": {
+//!     "avg_spread_pct", "max_spread_pct": max-min utilization across CPUs,
+//!     "avg_stddev_pct", "max_stddev_pct": utilization stddev across CPUs
+//!   },
+//!   "samples": [ {
+//!     "elapsed_ns", "busy_pct": [ per CPU ], "run_delay_ns": [ per CPU ] or null
+//!   } ]
+//! }
+//! ```
+//!
+//! Histogram buckets are only listed if they have samples; `value_ns` is the
+//! highest value that falls in the bucket.
+
+use crate::cpustat::CpuStatsReport;
+use crate::stats::TimerReport;
+use crate::worker::{self, WorkerReport};
+use clap::ArgEnum;
+use core::mem;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::ffi::CStr;
+use std::fs::File;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SCHEMA_VERSION: u32 = 1;
+
+/// Format of the final report.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Output {
+    Text,
+    Json,
+}
+
+/// Opens the file the report is written to, stdout if no path is given.
+pub fn open_output(path: &Option<String>) -> io::Result<Box<dyn Write>> {
+    Ok(match path {
+        Some(path) => Box::new(File::create(path)?),
+        None => Box::new(io::stdout()),
+    })
+}
+
+pub fn unix_time_ns(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+fn utsname_field(field: &[libc::c_char]) -> String {
+    unsafe { CStr::from_ptr(field.as_ptr()) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn machine_info(ncpus: usize, usable: &[usize]) -> Value {
+    let mut uts: libc::utsname = unsafe { mem::zeroed() };
+    unsafe { libc::uname(&mut uts) };
+
+    json!({
+        "cpus": ncpus,
+        "usable_cpus": usable,
+        "hostname": utsname_field(&uts.nodename),
+        "kernel": {
+            "sysname": utsname_field(&uts.sysname),
+            "release": utsname_field(&uts.release),
+            "version": utsname_field(&uts.version),
+            "machine": utsname_field(&uts.machine),
+        },
+    })
+}
+
+/// Everything measured during a run.
+pub struct Results<'a> {
+    pub start: SystemTime,
+    pub end: SystemTime,
+    pub timers: &'a [TimerReport],
+    pub workers: &'a [WorkerReport],
+    pub cpu_stats: Option<&'a CpuStatsReport>,
+    /// Times a SCHED_DEADLINE timer thread ran past its runtime.
+    pub runtime_overruns: Option<u64>,
+    pub violations: &'a [String],
+}
+
+pub fn to_json(config: &impl Serialize, machine: Value, results: &Results) -> Value {
+    let timers: Vec<Value> = results.timers.iter().map(TimerReport::to_json).collect();
+
+    json!({
+        "schema_version": SCHEMA_VERSION,
+        "start_time_ns": unix_time_ns(results.start),
+        "end_time_ns": unix_time_ns(results.end),
+        "config": config,
+        "machine": machine,
+        "timers": timers,
+        "aggregate": TimerReport::aggregate(results.timers).to_json(),
+        "workers": worker::to_json(results.workers),
+        "cpu_stats": results.cpu_stats.map(CpuStatsReport::to_json),
+        "deadline": results.runtime_overruns.map(|overruns| json!({
+            "runtime_overruns": overruns,
+        })),
+        "thresholds": {
+            "passed": results.violations.is_empty(),
+            "violations": results.violations,
+        },
+    })
+}
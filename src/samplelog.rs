@@ -0,0 +1,119 @@
+use crossbeam_queue::ArrayQueue;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Number of samples the log can hold before the writer thread drains them.
+const CAPACITY: usize = 1 << 16;
+
+/// How long the writer thread sleeps when there is nothing to write.
+const DRAIN_INTERVAL: Duration = Duration::from_millis(10);
+
+/// A single timer wakeup. Times are in nanoseconds of the measurement clock.
+#[derive(Clone, Copy, Debug)]
+pub struct Sample {
+    pub seq: u64,
+    /// CPU of the timer thread, or its index under SCHED_DEADLINE.
+    pub id: usize,
+    pub expected: u64,
+    pub actual: u64,
+    pub latency: u64,
+    pub overrun: u64,
+}
+
+/// Producer side of the sample log, used by the timer threads.
+#[derive(Clone)]
+pub struct SampleLog {
+    queue: Arc<ArrayQueue<Sample>>,
+    dropped: Arc<AtomicU64>,
+}
+
+impl SampleLog {
+    /// Queues a sample without blocking or allocating. If the writer thread
+    /// can't keep up the sample is dropped and accounted for.
+    pub fn push(&self, sample: Sample) {
+        if self.queue.push(sample).is_err() {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Non real-time thread streaming the queued samples to a CSV file.
+pub struct SampleWriter {
+    log: SampleLog,
+    done: Arc<AtomicBool>,
+    thread_handle: thread::JoinHandle<io::Result<()>>,
+}
+
+impl SampleWriter {
+    /// Creates the CSV file and starts the writer thread. `epoch_offset` is
+    /// added to the sample times to get the Unix timestamp column, and
+    /// `id_column` names the column telling the timer threads apart.
+    pub fn new(path: &str, epoch_offset: i64, id_column: &str) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(
+            out,
+            "seq,{},timestamp_ns,expected_ns,actual_ns,latency_ns,overruns",
+            id_column
+        )?;
+
+        let log = SampleLog {
+            queue: Arc::new(ArrayQueue::new(CAPACITY)),
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        let done = Arc::new(AtomicBool::new(false));
+
+        let queue = log.queue.clone();
+        let writer_done = done.clone();
+        let thread_handle = thread::spawn(move || {
+            loop {
+                // Read the flag before draining, so nothing queued before
+                // finish() is left behind.
+                let finished = writer_done.load(Ordering::Acquire);
+
+                while let Some(s) = queue.pop() {
+                    writeln!(
+                        out,
+                        "{},{},{},{},{},{},{}",
+                        s.seq,
+                        s.id,
+                        s.actual as i64 + epoch_offset,
+                        s.expected,
+                        s.actual,
+                        s.latency,
+                        s.overrun
+                    )?;
+                }
+
+                if finished {
+                    break;
+                }
+
+                thread::sleep(DRAIN_INTERVAL);
+            }
+
+            out.flush()
+        });
+
+        Ok(SampleWriter {
+            log,
+            done,
+            thread_handle,
+        })
+    }
+
+    pub fn log(&self) -> SampleLog {
+        self.log.clone()
+    }
+
+    /// Writes the remaining samples and returns how many were dropped.
+    /// Must be called after the timer threads have stopped.
+    pub fn finish(self) -> io::Result<u64> {
+        self.done.store(true, Ordering::Release);
+        self.thread_handle.join().unwrap()?;
+        Ok(self.log.dropped.load(Ordering::Relaxed))
+    }
+}
@@ -0,0 +1,418 @@
+use crate::task::TaskStats;
+use clap::ArgEnum;
+use hdrhistogram::Histogram;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::cmp;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Percentiles reported at the end of the run.
+pub const PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 99.99];
+
+/// Highest latency (in nanoseconds) the histogram can track; anything
+/// above it is clamped to this value.
+const MAX_TRACKABLE: u64 = 60_000_000_000;
+
+/// Unit used to display latencies. Samples are always kept in nanoseconds.
+#[derive(ArgEnum, Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Unit {
+    Ns,
+    Us,
+    Ms,
+}
+
+impl Unit {
+    pub fn format(&self, ns: f64) -> String {
+        match self {
+            Unit::Ns => format!("{:.0}ns", ns),
+            Unit::Us => format!("{:.3}us", ns / 1e3),
+            Unit::Ms => format!("{:.6}ms", ns / 1e6),
+        }
+    }
+}
+
+pub struct LatencyStats {
+    count: u64,
+    sum: u128,
+    sum_squares: u128,
+    min: u64,
+    max: u64,
+    hist: Histogram<u64>,
+}
+
+impl LatencyStats {
+    pub fn new() -> Self {
+        LatencyStats {
+            count: 0,
+            sum: 0,
+            sum_squares: 0,
+            min: u64::MAX,
+            max: 0,
+            // Preallocate the whole range so record() never allocates
+            // in the timer loop.
+            hist: Histogram::new_with_bounds(1, MAX_TRACKABLE, 3).unwrap(),
+        }
+    }
+
+    /// Records a latency sample, in nanoseconds.
+    pub fn record(&mut self, latency: u64) {
+        self.count += 1;
+        self.sum += latency as u128;
+        self.sum_squares += latency as u128 * latency as u128;
+        self.min = cmp::min(self.min, latency);
+        self.max = cmp::max(self.max, latency);
+        self.hist.saturating_record(latency);
+    }
+
+    pub fn merge(&mut self, other: &LatencyStats) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.sum_squares += other.sum_squares;
+        self.min = cmp::min(self.min, other.min);
+        self.max = cmp::max(self.max, other.max);
+        self.hist.add(&other.hist).unwrap();
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn min(&self) -> u64 {
+        if self.count == 0 {
+            0
+        } else {
+            self.min
+        }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn average(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    pub fn stddev(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        let mean = self.average();
+        let variance = self.sum_squares as f64 / self.count as f64 - mean * mean;
+        variance.max(0.0).sqrt()
+    }
+
+    /// The histogram reports the highest value of the bucket, which may be
+    /// above any sample actually seen.
+    pub fn percentile(&self, p: f64) -> u64 {
+        cmp::min(self.hist.value_at_quantile(p / 100.0), self.max)
+    }
+
+    pub fn write(&self, out: &mut dyn Write, unit: Unit) -> io::Result<()> {
+        if self.count == 0 {
+            return writeln!(out, "No latency samples were collected");
+        }
+
+        writeln!(out, "Samples = {}", self.count())?;
+        writeln!(out, "Minimum latency = {}", unit.format(self.min() as f64))?;
+        writeln!(out, "Average latency = {}", unit.format(self.average()))?;
+        writeln!(out, "Maximum latency = {}", unit.format(self.max() as f64))?;
+        writeln!(out, "Latency stddev = {}", unit.format(self.stddev()))?;
+        for p in PERCENTILES {
+            writeln!(
+                out,
+                "P{} latency = {}",
+                p,
+                unit.format(self.percentile(p) as f64)
+            )?;
+        }
+
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        let percentiles: serde_json::Map<String, Value> = PERCENTILES
+            .iter()
+            .map(|&p| (format!("p{}", p), json!(self.percentile(p))))
+            .collect();
+
+        let histogram: Vec<Value> = self
+            .hist
+            .iter_recorded()
+            .map(|v| json!({ "value_ns": v.value_iterated_to(), "count": v.count_at_value() }))
+            .collect();
+
+        json!({
+            "samples": self.count(),
+            "min_ns": self.min(),
+            "avg_ns": self.average(),
+            "max_ns": self.max(),
+            "stddev_ns": self.stddev(),
+            "percentiles_ns": percentiles,
+            "histogram": histogram,
+        })
+    }
+}
+
+/// Signed difference between the actual and the expected wakeup time.
+/// Negative values mean the thread woke up early.
+pub struct JitterStats {
+    count: u64,
+    sum: i128,
+    min: i64,
+    max: i64,
+}
+
+impl JitterStats {
+    pub fn new() -> Self {
+        JitterStats {
+            count: 0,
+            sum: 0,
+            min: i64::MAX,
+            max: i64::MIN,
+        }
+    }
+
+    /// Records a jitter sample, in nanoseconds.
+    pub fn record(&mut self, jitter: i64) {
+        self.count += 1;
+        self.sum += jitter as i128;
+        self.min = cmp::min(self.min, jitter);
+        self.max = cmp::max(self.max, jitter);
+    }
+
+    pub fn merge(&mut self, other: &JitterStats) {
+        self.count += other.count;
+        self.sum += other.sum;
+        self.min = cmp::min(self.min, other.min);
+        self.max = cmp::max(self.max, other.max);
+    }
+
+    pub fn average(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    pub fn write(&self, out: &mut dyn Write, unit: Unit) -> io::Result<()> {
+        if self.count == 0 {
+            return Ok(());
+        }
+
+        writeln!(out, "Minimum jitter = {}", unit.format(self.min as f64))?;
+        writeln!(out, "Average jitter = {}", unit.format(self.average()))?;
+        writeln!(out, "Maximum jitter = {}", unit.format(self.max as f64))
+    }
+
+    pub fn to_json(&self) -> Value {
+        if self.count == 0 {
+            return json!({ "samples": 0 });
+        }
+
+        json!({
+            "samples": self.count,
+            "min_ns": self.min,
+            "avg_ns": self.average(),
+            "max_ns": self.max,
+        })
+    }
+}
+
+/// Timer periods the thread missed because it was not able to handle the
+/// signal before the next expiration.
+pub struct OverrunStats {
+    total: u64,
+    max_streak: u64,
+}
+
+impl OverrunStats {
+    pub fn new() -> Self {
+        OverrunStats {
+            total: 0,
+            max_streak: 0,
+        }
+    }
+
+    /// Records the overrun count of a single wakeup, i.e. the number of
+    /// consecutive periods missed right before it.
+    pub fn record(&mut self, overrun: u64) {
+        self.total += overrun;
+        self.max_streak = cmp::max(self.max_streak, overrun);
+    }
+
+    pub fn merge(&mut self, other: &OverrunStats) {
+        self.total += other.total;
+        self.max_streak = cmp::max(self.max_streak, other.max_streak);
+    }
+
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        writeln!(out, "Timer overruns = {}", self.total)?;
+        writeln!(out, "Longest overrun streak = {}", self.max_streak)
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "total": self.total,
+            "max_streak": self.max_streak,
+        })
+    }
+}
+
+/// Tells the timer threads apart: the CPU they are pinned to, or their index
+/// under SCHED_DEADLINE, where they are not pinned.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimerLabel {
+    Cpu(usize),
+    Index(usize),
+}
+
+impl TimerLabel {
+    /// Heading of the column listing the threads.
+    pub fn heading(&self) -> &'static str {
+        match self {
+            TimerLabel::Cpu(_) => "CPU",
+            TimerLabel::Index(_) => "Timer",
+        }
+    }
+
+    pub fn number(&self) -> usize {
+        match *self {
+            TimerLabel::Cpu(n) | TimerLabel::Index(n) => n,
+        }
+    }
+
+    pub fn cpu(&self) -> Option<usize> {
+        match *self {
+            TimerLabel::Cpu(cpu) => Some(cpu),
+            TimerLabel::Index(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for TimerLabel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimerLabel::Cpu(cpu) => write!(f, "CPU {}", cpu),
+            TimerLabel::Index(index) => write!(f, "timer {}", index),
+        }
+    }
+}
+
+/// Everything a timer thread measured during the run.
+pub struct TimerReport {
+    /// Timer thread measured, None for the aggregate of several threads.
+    pub label: Option<TimerLabel>,
+    pub latency: LatencyStats,
+    pub jitter: Option<JitterStats>,
+    pub overruns: OverrunStats,
+    /// Periods that ended past their deadline, under SCHED_DEADLINE.
+    pub deadline_misses: Option<u64>,
+    /// Scheduler accounting of the thread during the run, if /proc has it.
+    pub task: Option<TaskStats>,
+}
+
+impl TimerReport {
+    /// Combines the reports of several timer threads into a single one.
+    pub fn aggregate(reports: &[TimerReport]) -> TimerReport {
+        let mut total = TimerReport {
+            label: None,
+            latency: LatencyStats::new(),
+            jitter: None,
+            overruns: OverrunStats::new(),
+            deadline_misses: None,
+            task: None,
+        };
+
+        for report in reports {
+            total.latency.merge(&report.latency);
+            total.overruns.merge(&report.overruns);
+            if let Some(jitter) = &report.jitter {
+                total
+                    .jitter
+                    .get_or_insert_with(JitterStats::new)
+                    .merge(jitter);
+            }
+            if let Some(misses) = report.deadline_misses {
+                *total.deadline_misses.get_or_insert(0) += misses;
+            }
+            if let Some(task) = &report.task {
+                match &mut total.task {
+                    Some(total) => total.merge(task),
+                    None => total.task = Some(*task),
+                }
+            }
+        }
+
+        total
+    }
+
+    pub fn write(&self, out: &mut dyn Write, unit: Unit) -> io::Result<()> {
+        self.latency.write(out, unit)?;
+        if let Some(jitter) = &self.jitter {
+            jitter.write(out, unit)?;
+        }
+        self.overruns.write(out)?;
+        if let Some(misses) = self.deadline_misses {
+            writeln!(out, "Deadline misses = {}", misses)?;
+        }
+        if let Some(task) = &self.task {
+            task.write(out, unit)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "cpu": self.label.and_then(|label| label.cpu()),
+            "timer": match self.label {
+                Some(TimerLabel::Index(index)) => Some(index),
+                _ => None,
+            },
+            "latency": self.latency.to_json(),
+            "jitter": self.jitter.as_ref().map(JitterStats::to_json),
+            "overruns": self.overruns.to_json(),
+            "deadline_misses": self.deadline_misses,
+            "task": self.task.map(TaskStats::to_json),
+        })
+    }
+}
+
+/// Writes one line per timer thread followed by the aggregated statistics.
+pub fn write_reports(out: &mut dyn Write, reports: &[TimerReport], unit: Unit) -> io::Result<()> {
+    if reports.len() > 1 {
+        let heading = reports[0].label.unwrap().heading();
+        writeln!(
+            out,
+            "{:>5} {:>10} {:>16} {:>16} {:>16} {:>16} {:>10}",
+            heading, "Samples", "Min", "Avg", "Max", "P99", "Overruns"
+        )?;
+        for report in reports {
+            let lat = &report.latency;
+            writeln!(
+                out,
+                "{:>5} {:>10} {:>16} {:>16} {:>16} {:>16} {:>10}",
+                report.label.unwrap().number(),
+                lat.count(),
+                unit.format(lat.min() as f64),
+                unit.format(lat.average()),
+                unit.format(lat.max() as f64),
+                unit.format(lat.percentile(99.0) as f64),
+                report.overruns.total(),
+            )?;
+        }
+        writeln!(out)?;
+        writeln!(out, "All {}s:", heading)?;
+    }
+
+    TimerReport::aggregate(reports).write(out, unit)
+}
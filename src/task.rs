@@ -0,0 +1,140 @@
+use crate::stats::Unit;
+use serde_json::{json, Value};
+use std::fs;
+use std::io::{self, Write};
+
+/// Looks up a field of /proc/<pid>/task/<tid>/sched, e.g. "se.nr_migrations".
+/// The file is only there if the kernel has scheduler debugging enabled.
+pub fn sched_field<'a>(sched: &'a str, field: &str) -> Option<&'a str> {
+    sched.lines().find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.trim() == field {
+            Some(value.trim())
+        } else {
+            None
+        }
+    })
+}
+
+/// Parses se.sum_exec_runtime, which the kernel prints in milliseconds with
+/// a fractional part, into nanoseconds.
+pub fn parse_sum_exec_runtime(sched: &str) -> Option<u64> {
+    let value = sched_field(sched, "se.sum_exec_runtime")?;
+    let (ms, frac) = value.split_once('.').unwrap_or((value, "0"));
+    let frac = format!("{:0<6}", frac);
+
+    let frac: u64 = frac.get(..6)?.parse().ok()?;
+    Some(ms.parse::<u64>().ok()? * 1_000_000 + frac)
+}
+
+/// Parses /proc/<pid>/task/<tid>/schedstat: time spent on the CPU, time
+/// spent waiting on a runqueue, both in nanoseconds, and timeslices run.
+pub fn parse_schedstat(schedstat: &str) -> Option<(u64, u64)> {
+    let mut fields = schedstat.split_whitespace().map(str::parse::<u64>);
+    let run = fields.next()?.ok()?;
+    let wait = fields.next()?.ok()?;
+    Some((run, wait))
+}
+
+/// Parses the voluntary and involuntary context switches out of
+/// /proc/<pid>/task/<tid>/status.
+pub fn parse_status(status: &str) -> Option<(u64, u64)> {
+    let field = |name: &str| -> Option<u64> {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))?
+            .trim()
+            .parse()
+            .ok()
+    };
+
+    Some((
+        field("voluntary_ctxt_switches")?,
+        field("nonvoluntary_ctxt_switches")?,
+    ))
+}
+
+fn read_task_file(tid: u64, name: &str) -> Option<String> {
+    fs::read_to_string(format!("/proc/self/task/{}/{}", tid, name)).ok()
+}
+
+/// Number of times the kernel migrated the thread, None if the kernel does
+/// not tell.
+pub fn nr_migrations(tid: u64) -> Option<u64> {
+    sched_field(&read_task_file(tid, "sched")?, "se.nr_migrations")?
+        .parse()
+        .ok()
+}
+
+/// Scheduler accounting of a thread. Runtime and wait time depend on kernel
+/// options, so they may be missing.
+#[derive(Clone, Copy)]
+pub struct TaskStats {
+    pub voluntary_switches: u64,
+    pub involuntary_switches: u64,
+    /// Time spent running, in nanoseconds.
+    pub runtime: Option<u64>,
+    /// Time spent runnable but waiting for the CPU, in nanoseconds.
+    pub wait: Option<u64>,
+}
+
+impl TaskStats {
+    /// Reads the current accounting of the thread `tid` of this process.
+    pub fn read(tid: u64) -> Option<TaskStats> {
+        let (voluntary_switches, involuntary_switches) =
+            parse_status(&read_task_file(tid, "status")?)?;
+        let schedstat = read_task_file(tid, "schedstat").and_then(|s| parse_schedstat(&s));
+        let runtime = read_task_file(tid, "sched")
+            .and_then(|s| parse_sum_exec_runtime(&s))
+            .or(schedstat.map(|(run, _)| run));
+
+        Some(TaskStats {
+            voluntary_switches,
+            involuntary_switches,
+            runtime,
+            wait: schedstat.map(|(_, wait)| wait),
+        })
+    }
+
+    /// What happened between `start` and `self`.
+    pub fn since(&self, start: &TaskStats) -> TaskStats {
+        TaskStats {
+            voluntary_switches: self.voluntary_switches - start.voluntary_switches,
+            involuntary_switches: self.involuntary_switches - start.involuntary_switches,
+            runtime: self
+                .runtime
+                .zip(start.runtime)
+                .map(|(end, start)| end - start),
+            wait: self.wait.zip(start.wait).map(|(end, start)| end - start),
+        }
+    }
+
+    pub fn merge(&mut self, other: &TaskStats) {
+        self.voluntary_switches += other.voluntary_switches;
+        self.involuntary_switches += other.involuntary_switches;
+        self.runtime = self.runtime.zip(other.runtime).map(|(a, b)| a + b);
+        self.wait = self.wait.zip(other.wait).map(|(a, b)| a + b);
+    }
+
+    pub fn write(&self, out: &mut dyn Write, unit: Unit) -> io::Result<()> {
+        let format =
+            |ns: Option<u64>| ns.map_or_else(|| "n/a".to_string(), |ns| unit.format(ns as f64));
+
+        writeln!(
+            out,
+            "Context switches = {} voluntary, {} involuntary",
+            self.voluntary_switches, self.involuntary_switches
+        )?;
+        writeln!(out, "Runtime = {}", format(self.runtime))?;
+        writeln!(out, "Runqueue wait = {}", format(self.wait))
+    }
+
+    pub fn to_json(self) -> Value {
+        json!({
+            "voluntary_switches": self.voluntary_switches,
+            "involuntary_switches": self.involuntary_switches,
+            "runtime_ns": self.runtime,
+            "wait_ns": self.wait,
+        })
+    }
+}
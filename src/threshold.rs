@@ -0,0 +1,54 @@
+use crate::stats::{TimerReport, Unit};
+
+/// Limits the run must stay within to pass, in nanoseconds.
+pub struct Thresholds {
+    pub max_latency: Option<u64>,
+    pub max_p99: Option<u64>,
+    pub max_overruns: Option<u64>,
+}
+
+impl Thresholds {
+    /// Returns a description of every limit that was exceeded. Latency
+    /// limits apply to each timer thread, the overrun limit to all of them
+    /// together.
+    pub fn check(&self, reports: &[TimerReport], unit: Unit) -> Vec<String> {
+        let mut violations = Vec::new();
+
+        for report in reports {
+            let label = report.label.unwrap();
+
+            if let Some(limit) = self.max_latency {
+                let max = report.latency.max();
+                if max > limit {
+                    violations.push(format!(
+                        "{}: maximum latency {} exceeds {}",
+                        label,
+                        unit.format(max as f64),
+                        unit.format(limit as f64)
+                    ));
+                }
+            }
+
+            if let Some(limit) = self.max_p99 {
+                let p99 = report.latency.percentile(99.0);
+                if report.latency.count() > 0 && p99 > limit {
+                    violations.push(format!(
+                        "{}: P99 latency {} exceeds {}",
+                        label,
+                        unit.format(p99 as f64),
+                        unit.format(limit as f64)
+                    ));
+                }
+            }
+        }
+
+        if let Some(limit) = self.max_overruns {
+            let total: u64 = reports.iter().map(|r| r.overruns.total()).sum();
+            if total > limit {
+                violations.push(format!("{} timer overruns exceed {}", total, limit));
+            }
+        }
+
+        violations
+    }
+}
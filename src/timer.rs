@@ -0,0 +1,552 @@
+use crate::deadline::{set_self_deadline, DeadlineConfig};
+use crate::live::LiveStats;
+use crate::samplelog::{Sample, SampleLog};
+use crate::stats::{JitterStats, LatencyStats, OverrunStats, TimerLabel, TimerReport};
+use crate::task::TaskStats;
+use crate::trace::Tracer;
+use affinity::*;
+use clap::ArgEnum;
+use core::mem;
+use errno::errno;
+use gettid::gettid;
+use libc::c_void;
+use scheduler::{set_self_policy, Policy};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Read;
+use std::os::unix::io::FromRawFd;
+use std::sync::{atomic::AtomicBool, atomic::Ordering, Arc};
+use std::time::Duration;
+use std::{cmp, error::Error, ops::Drop, ptr, sync::mpsc, thread};
+
+fn timespec_to_ns(ts: &libc::timespec) -> u64 {
+    ts.tv_sec as u64 * 1_000_000_000 + ts.tv_nsec as u64
+}
+
+fn ns_to_timespec(ns: u64) -> libc::timespec {
+    libc::timespec {
+        tv_sec: (ns / 1_000_000_000) as i64,
+        tv_nsec: (ns % 1_000_000_000) as i64,
+    }
+}
+
+fn duration_to_itimerspec(start: u64, dur: &Duration) -> libc::itimerspec {
+    let mut tmspec: libc::itimerspec = unsafe { mem::zeroed() };
+    tmspec.it_interval.tv_sec = dur.as_secs() as i64;
+    tmspec.it_interval.tv_nsec = dur.subsec_nanos() as i64;
+    tmspec.it_value = ns_to_timespec(start + dur.as_nanos() as u64);
+    tmspec
+}
+
+/// Current time of the given clock in nanoseconds.
+fn clock_now(clock: libc::clockid_t) -> u64 {
+    let mut ts: libc::timespec = unsafe { mem::zeroed() };
+    unsafe { libc::clock_gettime(clock, &mut ts) };
+    timespec_to_ns(&ts)
+}
+
+/// Clock source for the timers and the latency measurement.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Clock {
+    Monotonic,
+    /// Not slewed by NTP, only usable for relative measurement
+    MonotonicRaw,
+    Realtime,
+    Boottime,
+    Tai,
+}
+
+impl Clock {
+    /// Current time of the measurement clock in nanoseconds.
+    pub fn now(&self) -> u64 {
+        clock_now(self.id())
+    }
+
+    /// Clock used to read the current time.
+    fn id(&self) -> libc::clockid_t {
+        match self {
+            Clock::Monotonic => libc::CLOCK_MONOTONIC,
+            Clock::MonotonicRaw => libc::CLOCK_MONOTONIC_RAW,
+            Clock::Realtime => libc::CLOCK_REALTIME,
+            Clock::Boottime => libc::CLOCK_BOOTTIME,
+            Clock::Tai => libc::CLOCK_TAI,
+        }
+    }
+
+    /// Clock used to arm the timers. The kernel doesn't support timers on
+    /// CLOCK_MONOTONIC_RAW, so we fall back to CLOCK_MONOTONIC for it.
+    fn timer_id(&self) -> libc::clockid_t {
+        match self {
+            Clock::MonotonicRaw => libc::CLOCK_MONOTONIC,
+            _ => self.id(),
+        }
+    }
+
+    /// Whether expiration times of the timer clock can be compared with
+    /// readings of this clock, as absolute measurement does.
+    pub fn arms_timers(&self) -> bool {
+        self.id() == self.timer_id()
+    }
+}
+
+struct TimerId(*mut c_void);
+
+// A timer_t is just a handle to a kernel object, it can be used from any thread.
+unsafe impl Send for TimerId {}
+
+impl Drop for TimerId {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { libc::timer_delete(self.0) };
+        }
+    }
+}
+
+struct Timer {
+    id: TimerId,
+    start: u64,
+}
+
+impl Timer {
+    pub fn new(
+        thread_id: i32,
+        clock: libc::clockid_t,
+        dur: &Duration,
+    ) -> Result<Self, Box<dyn Error>> {
+        let mut timerid = TimerId(ptr::null_mut());
+        let mut sigev: libc::sigevent = unsafe { mem::zeroed() };
+
+        sigev.sigev_notify = libc::SIGEV_THREAD_ID;
+        sigev.sigev_signo = libc::SIGALRM;
+        sigev.sigev_notify_thread_id = thread_id;
+
+        let mut ret;
+        unsafe {
+            ret = libc::timer_create(
+                clock,
+                &mut sigev as *mut libc::sigevent,
+                &mut timerid.0 as *mut *mut libc::c_void,
+            );
+        }
+
+        if ret < 0 {
+            return Err(Box::new(errno()));
+        }
+
+        // Arm the timer at an absolute time, so the n-th expiration is
+        // expected exactly at start + n * interval.
+        let start = clock_now(clock);
+        let tmspec = duration_to_itimerspec(start, dur);
+
+        unsafe {
+            ret = libc::timer_settime(timerid.0, libc::TIMER_ABSTIME, &tmspec, ptr::null_mut());
+        }
+
+        if ret < 0 {
+            Err(Box::new(errno()))
+        } else {
+            Ok(Timer { id: timerid, start })
+        }
+    }
+
+    /// Time the timer was armed at, in nanoseconds of the timer clock.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Number of expirations that happened between the last delivered
+    /// signal and its acceptance by the thread.
+    pub fn overrun(&self) -> Result<u64, Box<dyn Error>> {
+        let ret = unsafe { libc::timer_getoverrun(self.id.0) };
+
+        if ret < 0 {
+            Err(Box::new(errno()))
+        } else {
+            Ok(ret as u64)
+        }
+    }
+}
+
+/// How the latency of each timer expiration is computed.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Measure {
+    /// Relative to the previous wakeup
+    Relative,
+    /// Relative to the absolute expected expiration time, like cyclictest
+    Absolute,
+}
+
+/// Mechanism the timer thread uses to wait for the next period.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Mode {
+    /// POSIX timer delivering SIGALRM to the thread
+    Signal,
+    /// clock_nanosleep(TIMER_ABSTIME) loop
+    Nanosleep,
+    /// Blocking read on a timerfd
+    Timerfd,
+}
+
+/// Source of periodic wakeups for the timer thread.
+trait Ticker {
+    /// Time the first period started at, in nanoseconds of the timer clock.
+    fn start(&self) -> u64;
+
+    /// Blocks until the next period expires and returns how many periods
+    /// were missed before it.
+    fn wait(&mut self) -> Result<u64, Box<dyn Error>>;
+}
+
+struct SignalTicker {
+    timer: Timer,
+    sigset: libc::sigset_t,
+}
+
+impl SignalTicker {
+    fn new(clock: libc::clockid_t, interval: &Duration) -> Result<Self, Box<dyn Error>> {
+        // SIGALRM is directed to this thread only and consumed synchronously
+        // with sigwaitinfo(), so every timer thread gets its own expirations.
+        let mut sigset: libc::sigset_t = unsafe { mem::zeroed() };
+        let ret = unsafe {
+            libc::sigemptyset(&mut sigset);
+            libc::sigaddset(&mut sigset, libc::SIGALRM);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &sigset, ptr::null_mut())
+        };
+
+        if ret != 0 {
+            return Err(Box::new(errno::Errno(ret)));
+        }
+
+        let timer = Timer::new(gettid() as i32, clock, interval)?;
+        Ok(SignalTicker { timer, sigset })
+    }
+}
+
+impl Ticker for SignalTicker {
+    fn start(&self) -> u64 {
+        self.timer.start()
+    }
+
+    fn wait(&mut self) -> Result<u64, Box<dyn Error>> {
+        let mut info: libc::siginfo_t = unsafe { mem::zeroed() };
+
+        while unsafe { libc::sigwaitinfo(&self.sigset, &mut info) } < 0 {
+            let err = errno();
+            if err.0 != libc::EINTR {
+                return Err(Box::new(err));
+            }
+        }
+
+        self.timer.overrun()
+    }
+}
+
+struct NanosleepTicker {
+    clock: libc::clockid_t,
+    start: u64,
+    next: u64,
+    interval: u64,
+}
+
+impl NanosleepTicker {
+    fn new(clock: libc::clockid_t, interval: &Duration) -> Self {
+        let start = clock_now(clock);
+        NanosleepTicker {
+            clock,
+            start,
+            next: start,
+            interval: interval.as_nanos() as u64,
+        }
+    }
+}
+
+impl Ticker for NanosleepTicker {
+    fn start(&self) -> u64 {
+        self.start
+    }
+
+    fn wait(&mut self) -> Result<u64, Box<dyn Error>> {
+        self.next += self.interval;
+        let ts = ns_to_timespec(self.next);
+
+        loop {
+            let ret = unsafe {
+                libc::clock_nanosleep(self.clock, libc::TIMER_ABSTIME, &ts, ptr::null_mut())
+            };
+
+            match ret {
+                0 => break,
+                libc::EINTR => continue,
+                err => return Err(Box::new(errno::Errno(err))),
+            }
+        }
+
+        // Skip the deadlines that already passed, the same way a timer
+        // overrun would.
+        let now = clock_now(self.clock);
+        let missed = now.saturating_sub(self.next) / self.interval;
+        self.next += missed * self.interval;
+
+        Ok(missed)
+    }
+}
+
+struct TimerfdTicker {
+    file: File,
+    start: u64,
+}
+
+impl TimerfdTicker {
+    fn new(clock: libc::clockid_t, interval: &Duration) -> Result<Self, Box<dyn Error>> {
+        let fd = unsafe { libc::timerfd_create(clock, libc::TFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Box::new(errno()));
+        }
+
+        let file = unsafe { File::from_raw_fd(fd) };
+
+        let start = clock_now(clock);
+        let tmspec = duration_to_itimerspec(start, interval);
+
+        let ret =
+            unsafe { libc::timerfd_settime(fd, libc::TFD_TIMER_ABSTIME, &tmspec, ptr::null_mut()) };
+
+        if ret < 0 {
+            Err(Box::new(errno()))
+        } else {
+            Ok(TimerfdTicker { file, start })
+        }
+    }
+}
+
+impl Ticker for TimerfdTicker {
+    fn start(&self) -> u64 {
+        self.start
+    }
+
+    fn wait(&mut self) -> Result<u64, Box<dyn Error>> {
+        let mut buf = [0u8; 8];
+        self.file.read_exact(&mut buf)?;
+        Ok(u64::from_ne_bytes(buf).saturating_sub(1))
+    }
+}
+
+fn new_ticker(
+    mode: Mode,
+    clock: Clock,
+    interval: &Duration,
+) -> Result<Box<dyn Ticker>, Box<dyn Error>> {
+    let clock = clock.timer_id();
+    Ok(match mode {
+        Mode::Signal => Box::new(SignalTicker::new(clock, interval)?),
+        Mode::Nanosleep => Box::new(NanosleepTicker::new(clock, interval)),
+        Mode::Timerfd => Box::new(TimerfdTicker::new(clock, interval)?),
+    })
+}
+
+/// Settings shared by all timer threads.
+#[derive(Clone, Copy, Debug)]
+pub struct TimerConfig {
+    pub interval: Duration,
+    pub priority: u32,
+    pub mode: Mode,
+    pub measure: Measure,
+    pub clock: Clock,
+    /// Stop tracing on the first latency above this, in nanoseconds.
+    pub breaktrace: Option<u64>,
+    /// Mark every latency above this in the trace, in nanoseconds.
+    pub trace_threshold: Option<u64>,
+    /// Run under SCHED_DEADLINE instead of SCHED_FIFO, not pinned to the
+    /// timer CPU.
+    pub deadline: Option<DeadlineConfig>,
+}
+
+/// Optional consumers of the samples, besides the final report.
+#[derive(Clone, Default)]
+pub struct TimerOutputs {
+    pub sample_log: Option<SampleLog>,
+    pub live: Option<Arc<LiveStats>>,
+    pub tracer: Option<Arc<Tracer>>,
+}
+
+pub struct TimerThread {
+    thread_handle: Option<thread::JoinHandle<Option<TimerReport>>>,
+    quit: Arc<AtomicBool>,
+}
+
+impl TimerThread {
+    pub fn new(
+        label: TimerLabel,
+        config: &TimerConfig,
+        outputs: TimerOutputs,
+        quit: Arc<AtomicBool>,
+    ) -> Result<Self, Box<dyn Error>> {
+        let TimerConfig {
+            interval,
+            priority,
+            mode,
+            measure,
+            clock,
+            breaktrace,
+            trace_threshold,
+            deadline,
+        } = *config;
+
+        let (tx, rx) = mpsc::channel();
+        let delay = interval;
+        let thread_quit = quit.clone();
+
+        let handle = thread::spawn(move || {
+            let setup = || -> Result<Box<dyn Ticker>, Box<dyn Error>> {
+                match &deadline {
+                    // Admission control only accepts deadline tasks allowed
+                    // on every CPU of their root domain, so they stay unpinned
+                    Some(deadline) => set_self_deadline(deadline)?,
+                    None => {
+                        if let Some(cpu) = label.cpu() {
+                            set_thread_affinity([cpu])?;
+                        }
+                        set_self_policy(Policy::Fifo, priority as i32).map_err(|_| {
+                            format!("cannot set SCHED_FIFO priority {}: {}", priority, errno())
+                        })?;
+                    }
+                }
+
+                let ticker = new_ticker(mode, clock, &delay)?;
+                if let Some(tracer) = &outputs.tracer {
+                    tracer.armed(label)?;
+                }
+                Ok(ticker)
+            };
+
+            let mut ticker = match setup() {
+                Ok(ticker) => {
+                    tx.send(Ok(())).unwrap();
+                    ticker
+                }
+                Err(e) => {
+                    tx.send(Err(e.to_string())).unwrap();
+                    return None;
+                }
+            };
+
+            let tid = gettid();
+            let task_start = TaskStats::read(tid);
+
+            let delay = delay.as_nanos() as u64;
+            let mut checkpoint = clock.now();
+            // Last expiration accounted for, in absolute mode
+            let mut last = ticker.start();
+            let mut seq: u64 = 0;
+
+            let mut latency = LatencyStats::new();
+            let mut jitter = JitterStats::new();
+            let mut overruns = OverrunStats::new();
+            let mut deadline_misses = 0;
+
+            loop {
+                let overrun = ticker.wait().unwrap();
+
+                if thread_quit.load(Ordering::Acquire) {
+                    break;
+                }
+
+                overruns.record(overrun);
+
+                let now = clock.now();
+                let (expected, lat) = match measure {
+                    Measure::Relative => {
+                        let expected = checkpoint + delay;
+                        let lat = now.saturating_sub(expected);
+                        checkpoint = clock.now();
+                        (expected, lat)
+                    }
+                    Measure::Absolute => {
+                        // Late against the first expiration we missed, like
+                        // cyclictest; the kernel coalesced the ones after it
+                        // into this same wakeup.
+                        let expected = last + delay;
+                        last += (overrun + 1) * delay;
+
+                        let diff = now as i64 - expected as i64;
+                        jitter.record(diff);
+                        (expected, cmp::max(diff, 0) as u64)
+                    }
+                };
+
+                latency.record(lat);
+
+                if let Some(live) = &outputs.live {
+                    live.record(lat, overrun);
+                }
+
+                if let Some(tracer) = &outputs.tracer {
+                    if trace_threshold.is_some_and(|limit| lat > limit) {
+                        tracer.sample(seq, label, lat).unwrap();
+                    }
+                    if breaktrace.is_some_and(|limit| lat > limit) {
+                        tracer.breaktrace(seq, label, lat).unwrap();
+                    }
+                }
+
+                if let Some(log) = &outputs.sample_log {
+                    log.push(Sample {
+                        seq,
+                        id: label.number(),
+                        expected,
+                        actual: now,
+                        latency: lat,
+                        overrun,
+                    });
+                }
+                // The job of this period is done, did it meet its deadline?
+                if let Some(deadline) = &deadline {
+                    if clock.now() > expected + deadline.deadline.as_nanos() as u64 {
+                        deadline_misses += 1;
+                    }
+                }
+                seq += 1;
+            }
+
+            Some(TimerReport {
+                label: Some(label),
+                latency,
+                jitter: (measure == Measure::Absolute).then_some(jitter),
+                overruns,
+                deadline_misses: deadline.map(|_| deadline_misses),
+                task: task_start
+                    .zip(TaskStats::read(tid))
+                    .map(|(start, end)| end.since(&start)),
+            })
+        });
+
+        rx.recv()??;
+
+        Ok(TimerThread {
+            thread_handle: Some(handle),
+            quit,
+        })
+    }
+
+    pub fn join(&mut self) -> thread::Result<TimerReport> {
+        self.thread_handle
+            .take()
+            .unwrap()
+            .join()
+            .map(|report| report.unwrap())
+    }
+}
+
+impl Drop for TimerThread {
+    /// Stops the whole test if we bail out before joining the thread, so it
+    /// does not keep the tracer and the sample log alive.
+    fn drop(&mut self) {
+        if let Some(handle) = self.thread_handle.take() {
+            self.quit.store(true, Ordering::Release);
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,345 @@
+use crate::stats::TimerLabel;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Cursor, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Mutex, PoisonError, Weak};
+
+/// Scheduler events enabled with --trace-events.
+const SCHED_EVENTS: [&str; 3] = ["sched_switch", "sched_migrate_task", "sched_wakeup"];
+
+/// Default tracefs mount points, in order of preference.
+const TRACEFS_PATHS: [&str; 2] = ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+
+fn find_tracefs() -> Result<PathBuf, Box<dyn Error>> {
+    TRACEFS_PATHS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.join("trace_marker").exists())
+        .ok_or_else(|| "tracefs not found, is it mounted?".into())
+}
+
+/// How the trace markers name a timer thread.
+fn marker_label(timer: TimerLabel) -> (&'static str, usize) {
+    match timer {
+        TimerLabel::Cpu(cpu) => ("cpu", cpu),
+        TimerLabel::Index(index) => ("timer", index),
+    }
+}
+
+fn read_setting(path: &Path) -> Result<String, Box<dyn Error>> {
+    Ok(fs::read_to_string(path)
+        .map_err(|e| format!("{}: {}", path.display(), e))?
+        .trim()
+        .to_string())
+}
+
+fn write_setting(path: &Path, value: &str) -> Result<(), Box<dyn Error>> {
+    fs::write(path, value).map_err(|e| format!("{}: {}", path.display(), e).into())
+}
+
+/// Held while putting the settings back, so exiting the process cannot leave
+/// them half written.
+static RESTORING: Mutex<()> = Mutex::new(());
+
+/// Sample that made us stop tracing.
+pub struct Trigger {
+    pub seq: u64,
+    pub timer: TimerLabel,
+    pub latency: u64,
+}
+
+/// Access to the ftrace buffer. The files used from the timer threads are
+/// opened up front, so the hot path never allocates.
+pub struct Tracer {
+    root: PathBuf,
+    marker: File,
+    tracing_on: File,
+    /// Settings we changed and their original values.
+    saved: Vec<(PathBuf, String)>,
+    triggered: AtomicBool,
+    trigger_seq: AtomicU64,
+    trigger_number: AtomicUsize,
+    trigger_pinned: AtomicBool,
+    trigger_latency: AtomicU64,
+}
+
+impl Tracer {
+    /// Opens the tracefs at `root`, or the default mount point. If
+    /// `sched_events` is set the scheduler events are enabled and tracing is
+    /// turned on; everything is put back when the tracer is dropped.
+    pub fn new(root: Option<&str>, sched_events: bool) -> Result<Self, Box<dyn Error>> {
+        let root = match root {
+            Some(root) => PathBuf::from(root),
+            None => find_tracefs()?,
+        };
+
+        let open = |name: &str| -> Result<File, Box<dyn Error>> {
+            let path = root.join(name);
+            OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e).into())
+        };
+
+        let marker = open("trace_marker")?;
+        let tracing_on = open("tracing_on")?;
+
+        let mut tracer = Tracer {
+            root,
+            marker,
+            tracing_on,
+            saved: Vec::new(),
+            triggered: AtomicBool::new(false),
+            trigger_seq: AtomicU64::new(0),
+            trigger_number: AtomicUsize::new(0),
+            trigger_pinned: AtomicBool::new(false),
+            trigger_latency: AtomicU64::new(0),
+        };
+
+        if sched_events {
+            for event in SCHED_EVENTS {
+                tracer.set(&format!("events/sched/{}/enable", event), "1")?;
+            }
+            tracer.set("tracing_on", "1")?;
+        }
+
+        Ok(tracer)
+    }
+
+    /// Changes a tracefs setting, remembering its original value.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let path = self.root.join(name);
+        let old = read_setting(&path)?;
+        write_setting(&path, value)?;
+        self.saved.push((path, old));
+        Ok(())
+    }
+
+    /// Writes a message to the trace buffer. Formatting happens on the
+    /// stack, so this is safe to call from the timer threads.
+    pub fn mark(&self, args: fmt::Arguments) -> io::Result<()> {
+        let mut buf = Cursor::new([0u8; 256]);
+        // A message too long for the buffer is just truncated
+        let _ = buf.write_fmt(args);
+        let len = buf.position() as usize;
+        (&self.marker).write_all(&buf.get_ref()[..len])
+    }
+
+    /// Marks a change of test phase in the trace buffer.
+    pub fn phase(&self, phase: &str) -> io::Result<()> {
+        self.mark(format_args!("stress-lb: {}\n", phase))
+    }
+
+    /// Marks a timer thread ready to measure.
+    pub fn armed(&self, timer: TimerLabel) -> io::Result<()> {
+        match timer {
+            TimerLabel::Cpu(cpu) => self.mark(format_args!("stress-lb: cpu {} timer armed\n", cpu)),
+            TimerLabel::Index(index) => {
+                self.mark(format_args!("stress-lb: timer {} armed\n", index))
+            }
+        }
+    }
+
+    /// Marks a sample above the trace threshold.
+    pub fn sample(&self, seq: u64, timer: TimerLabel, latency: u64) -> io::Result<()> {
+        let (kind, number) = marker_label(timer);
+        self.mark(format_args!(
+            "stress-lb: {} {} seq {} latency {}ns\n",
+            kind, number, seq, latency
+        ))
+    }
+
+    /// Stops tracing on the first sample above the threshold, leaving a
+    /// marker at the point the latency was observed. Returns true for the
+    /// sample that triggered it.
+    pub fn breaktrace(&self, seq: u64, timer: TimerLabel, latency: u64) -> io::Result<bool> {
+        if self.triggered.swap(true, Ordering::AcqRel) {
+            return Ok(false);
+        }
+
+        self.trigger_seq.store(seq, Ordering::Relaxed);
+        self.trigger_number.store(timer.number(), Ordering::Relaxed);
+        self.trigger_pinned
+            .store(timer.cpu().is_some(), Ordering::Relaxed);
+        self.trigger_latency.store(latency, Ordering::Relaxed);
+
+        let (kind, number) = marker_label(timer);
+        self.mark(format_args!(
+            "stress-lb: breaktrace: {} {} seq {} latency {}ns\n",
+            kind, number, seq, latency
+        ))?;
+        (&self.tracing_on).write_all(b"0")?;
+        Ok(true)
+    }
+
+    pub fn trigger(&self) -> Option<Trigger> {
+        if !self.triggered.load(Ordering::Acquire) {
+            return None;
+        }
+
+        let number = self.trigger_number.load(Ordering::Relaxed);
+        Some(Trigger {
+            seq: self.trigger_seq.load(Ordering::Relaxed),
+            timer: if self.trigger_pinned.load(Ordering::Relaxed) {
+                TimerLabel::Cpu(number)
+            } else {
+                TimerLabel::Index(number)
+            },
+            latency: self.trigger_latency.load(Ordering::Relaxed),
+        })
+    }
+
+    /// Puts back every setting we changed. If tracing was stopped by
+    /// breaktrace it is left off, so the buffer is kept for inspection.
+    fn restore(&self) -> Result<(), Box<dyn Error>> {
+        let tracing_on = self.root.join("tracing_on");
+        let stopped = self.triggered.load(Ordering::Acquire);
+
+        for (path, value) in self.saved.iter().rev() {
+            if stopped && *path == tracing_on {
+                continue;
+            }
+            write_setting(path, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Tracer {
+    fn drop(&mut self) {
+        let _restoring = RESTORING.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Err(e) = self.restore() {
+            eprintln!("Cannot restore the tracing settings: {}", e);
+        }
+    }
+}
+
+/// Exits the process right away, putting back the settings of `tracer` if
+/// it was not dropped yet.
+pub fn exit_restoring(tracer: Option<&Weak<Tracer>>, code: i32) -> ! {
+    let _restoring = RESTORING.lock().unwrap_or_else(PoisonError::into_inner);
+    // Kept until the exit, dropping it would take the lock again
+    let tracer = tracer.and_then(Weak::upgrade);
+    if let Some(tracer) = &tracer {
+        if let Err(e) = tracer.restore() {
+            eprintln!("Cannot restore the tracing settings: {}", e);
+        }
+    }
+    process::exit(code)
+}
+
+/// Waits for an exit_restoring() in progress, so returning from main() does
+/// not cut it short. Call it once every tracer was dropped.
+pub fn wait_for_restore() {
+    drop(RESTORING.lock().unwrap_or_else(PoisonError::into_inner));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, process};
+
+    /// Creates the tracefs files the tracer uses under a temporary directory.
+    fn fake_tracefs(name: &str, tracing_on: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("stress-lb-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&root);
+
+        for event in SCHED_EVENTS {
+            let dir = root.join("events/sched").join(event);
+            fs::create_dir_all(&dir).unwrap();
+            fs::write(dir.join("enable"), "0\n").unwrap();
+        }
+        fs::write(root.join("trace_marker"), "").unwrap();
+        fs::write(root.join("tracing_on"), tracing_on).unwrap();
+
+        root
+    }
+
+    fn setting(root: &Path, name: &str) -> String {
+        read_setting(&root.join(name)).unwrap()
+    }
+
+    fn event_enabled(root: &Path, event: &str) -> String {
+        setting(root, &format!("events/sched/{}/enable", event))
+    }
+
+    #[test]
+    fn enables_and_restores_events() {
+        let root = fake_tracefs("events", "0\n");
+
+        let tracer = Tracer::new(root.to_str(), true).unwrap();
+        for event in SCHED_EVENTS {
+            assert_eq!(event_enabled(&root, event), "1");
+        }
+        assert_eq!(setting(&root, "tracing_on"), "1");
+
+        drop(tracer);
+        for event in SCHED_EVENTS {
+            assert_eq!(event_enabled(&root, event), "0");
+        }
+        assert_eq!(setting(&root, "tracing_on"), "0");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn leaves_settings_alone_without_events() {
+        let root = fake_tracefs("no-events", "1\n");
+
+        let tracer = Tracer::new(root.to_str(), false).unwrap();
+        tracer.phase("workers started").unwrap();
+        drop(tracer);
+
+        for event in SCHED_EVENTS {
+            assert_eq!(event_enabled(&root, event), "0");
+        }
+        assert_eq!(setting(&root, "tracing_on"), "1");
+        assert_eq!(setting(&root, "trace_marker"), "stress-lb: workers started");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn breaktrace_fires_once() {
+        let root = fake_tracefs("breaktrace", "1\n");
+
+        let tracer = Tracer::new(root.to_str(), false).unwrap();
+        assert!(tracer.trigger().is_none());
+        assert!(tracer.breaktrace(7, TimerLabel::Cpu(2), 1500).unwrap());
+        assert!(!tracer.breaktrace(8, TimerLabel::Cpu(3), 2500).unwrap());
+
+        let trigger = tracer.trigger().unwrap();
+        assert_eq!(
+            (trigger.seq, trigger.timer, trigger.latency),
+            (7, TimerLabel::Cpu(2), 1500)
+        );
+        assert_eq!(
+            setting(&root, "trace_marker"),
+            "stress-lb: breaktrace: cpu 2 seq 7 latency 1500ns"
+        );
+        assert_eq!(setting(&root, "tracing_on"), "0");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn keeps_tracing_off_after_breaktrace() {
+        let root = fake_tracefs("restore", "1\n");
+
+        let tracer = Tracer::new(root.to_str(), true).unwrap();
+        tracer.breaktrace(0, TimerLabel::Cpu(0), 1000).unwrap();
+        drop(tracer);
+
+        for event in SCHED_EVENTS {
+            assert_eq!(event_enabled(&root, event), "0");
+        }
+        assert_eq!(setting(&root, "tracing_on"), "0");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,640 @@
+use crate::cpus::format_cpulist;
+use crate::policy::WorkerPolicy;
+use crate::task::{nr_migrations, TaskStats};
+use crate::workload::{new_workloads, xorshift64, Kicker, WorkloadConfig};
+use affinity::set_thread_affinity;
+use gettid::gettid;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn current_cpu() -> i32 {
+    unsafe { libc::sched_getcpu() }
+}
+
+/// A counter on a cache line of its own, so workers publishing their
+/// progress do not slow each other down.
+#[repr(align(64))]
+struct PaddedCounter(AtomicU64);
+
+/// Iterations done so far by each worker thread.
+struct Progress {
+    counters: Vec<PaddedCounter>,
+}
+
+impl Progress {
+    fn new(num_threads: usize) -> Self {
+        Progress {
+            counters: (0..num_threads)
+                .map(|_| PaddedCounter(AtomicU64::new(0)))
+                .collect(),
+        }
+    }
+
+    fn publish(&self, id: usize, iterations: u64) {
+        self.counters[id].0.store(iterations, Ordering::Relaxed);
+    }
+}
+
+/// What the load balancer did to a worker thread.
+pub struct WorkerReport {
+    pub id: usize,
+    pub policy: WorkerPolicy,
+    pub iterations: u64,
+    /// Time the worker spent running its workload.
+    pub elapsed: Duration,
+    /// Iterations done on each CPU, as (cpu, iterations).
+    pub cpu_iterations: Vec<(usize, u64)>,
+    /// CPU changes the thread saw itself, by polling sched_getcpu().
+    pub observed_migrations: u64,
+    /// Migrations accounted by the kernel, if it exposes them.
+    pub kernel_migrations: Option<u64>,
+    /// Scheduler accounting of the thread during the run, if /proc has it.
+    pub task: Option<TaskStats>,
+    /// Workload specific counters, as (name, count).
+    pub workload: Vec<(&'static str, u64)>,
+    pub duty_cycle: Option<DutyCycleReport>,
+}
+
+impl WorkerReport {
+    /// Iterations per second.
+    pub fn throughput(&self) -> f64 {
+        let secs = self.elapsed.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.iterations as f64 / secs
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "id": self.id,
+            "policy": self.policy.to_string(),
+            "iterations": self.iterations,
+            "elapsed_ns": self.elapsed.as_nanos() as u64,
+            "iterations_per_sec": self.throughput(),
+            "migrations": {
+                "observed": self.observed_migrations,
+                "kernel": self.kernel_migrations,
+            },
+            "task": self.task.map(TaskStats::to_json),
+            "workload": counters_to_json(&self.workload),
+            "duty_cycle": self.duty_cycle.map(|duty| json!({
+                "requested": duty.requested,
+                "achieved": duty.achieved,
+            })),
+        })
+    }
+}
+
+/// The worker threads of a run.
+pub struct Workers {
+    kicker: Kicker,
+    handles: Vec<thread::JoinHandle<Option<WorkerReport>>>,
+}
+
+impl Workers {
+    /// Waits for the workers to finish. Must be called after quit is set.
+    pub fn join(self) -> thread::Result<Vec<WorkerReport>> {
+        let mut reports = Vec::new();
+
+        // Wake up the workers sleeping in their duty cycle or waiting for a
+        // token from a worker that already left
+        for handle in &self.handles {
+            handle.thread().unpark();
+        }
+        self.kicker.kick();
+
+        for handle in self.handles {
+            // Only set up threads are joined, and those always report
+            reports.push(handle.join()?.unwrap());
+        }
+
+        Ok(reports)
+    }
+}
+
+/// Makes a worker alternate between running and sleeping, so it keeps
+/// waking up and has to be placed on a CPU again.
+#[derive(Clone, Copy, Debug)]
+pub struct DutyCycleConfig {
+    pub run: Duration,
+    pub sleep: Duration,
+    /// Every period is randomly stretched or shrunk by up to this fraction.
+    pub jitter: f64,
+}
+
+impl DutyCycleConfig {
+    /// Fraction of the time the worker is meant to run.
+    pub fn requested(&self) -> f64 {
+        let run = self.run.as_secs_f64();
+        run / (run + self.sleep.as_secs_f64())
+    }
+}
+
+/// Fraction of the time a worker was meant to run and actually got a CPU.
+#[derive(Clone, Copy)]
+pub struct DutyCycleReport {
+    pub requested: f64,
+    /// CPU time of the thread over the time it ran its workload, if /proc
+    /// has it. Falls short of the request when the CPUs are oversubscribed.
+    pub achieved: Option<f64>,
+}
+
+/// State of the duty cycle of a worker.
+struct DutyCycle {
+    config: DutyCycleConfig,
+    rng: u64,
+    phase_start: Instant,
+    run_length: Duration,
+}
+
+impl DutyCycle {
+    fn new(config: DutyCycleConfig, seed: u64) -> Self {
+        let mut duty = DutyCycle {
+            config,
+            rng: seed | 1,
+            phase_start: Instant::now(),
+            run_length: Duration::ZERO,
+        };
+        duty.run_length = duty.randomize(config.run);
+        duty
+    }
+
+    fn randomize(&mut self, length: Duration) -> Duration {
+        if self.config.jitter == 0.0 {
+            return length;
+        }
+
+        // Uniform in [-jitter, jitter]
+        let r = xorshift64(&mut self.rng) as f64 / u64::MAX as f64;
+        length.mul_f64(1.0 + self.config.jitter * (2.0 * r - 1.0))
+    }
+
+    /// Sleeps if the worker has used up its run period. Called from the
+    /// progress callback of the workload, so the run period is only as
+    /// precise as the time between two callbacks. The sleep is cut short
+    /// when `quit` is set and the thread unparked.
+    fn tick(&mut self, quit: &AtomicBool) {
+        if self.phase_start.elapsed() < self.run_length {
+            return;
+        }
+
+        let wake = Instant::now() + self.randomize(self.config.sleep);
+        while !quit.load(Ordering::Acquire) {
+            match wake.checked_duration_since(Instant::now()) {
+                Some(left) => thread::park_timeout(left),
+                None => break,
+            }
+        }
+
+        self.phase_start = Instant::now();
+        self.run_length = self.randomize(self.config.run);
+    }
+
+    /// `runtime` is the CPU time of the thread, in nanoseconds, over the
+    /// `elapsed` time it ran its workload.
+    fn finish(self, runtime: Option<u64>, elapsed: Duration) -> DutyCycleReport {
+        let secs = elapsed.as_secs_f64();
+
+        DutyCycleReport {
+            requested: self.config.requested(),
+            achieved: runtime.map(|ns| {
+                if secs == 0.0 {
+                    0.0
+                } else {
+                    ns as f64 / 1e9 / secs
+                }
+            }),
+        }
+    }
+}
+
+/// Settings shared by all worker threads.
+#[derive(Clone, Debug)]
+pub struct WorkerConfig {
+    pub threads_per_core: usize,
+    pub workload: WorkloadConfig,
+    /// Policies the worker threads take in turn.
+    pub policies: Vec<WorkerPolicy>,
+    /// Run continuously if None.
+    pub duty_cycle: Option<DutyCycleConfig>,
+}
+
+pub fn run_worker_threads(
+    cpus: Vec<usize>,
+    config: &WorkerConfig,
+    quit: Arc<AtomicBool>,
+) -> Result<Workers, Box<dyn Error>> {
+    let num_threads = cpus.len() * config.threads_per_core;
+    let progress = Arc::new(Progress::new(num_threads));
+
+    eprintln!(
+        "Starting {} worker threads on CPUs {}...",
+        num_threads,
+        format_cpulist(&cpus)
+    );
+
+    let mut kicker = Kicker::default();
+    let workloads = new_workloads(&config.workload, num_threads, &mut kicker)?;
+
+    let start = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    let handles: Vec<thread::JoinHandle<Option<WorkerReport>>> = workloads
+        .into_iter()
+        .enumerate()
+        .map(|(id, mut workload)| {
+            let myquit = quit.clone();
+            let core_mask = cpus.clone();
+            let progress = progress.clone();
+            let duty_config = config.duty_cycle;
+            let policy = config.policies[id % config.policies.len()];
+            let tx = tx.clone();
+            let start = start.clone();
+            thread::spawn(move || {
+                let mut setup = || -> Result<(), Box<dyn Error>> {
+                    set_thread_affinity(&core_mask)?;
+                    policy.apply()?;
+                    workload.setup();
+                    Ok(())
+                };
+
+                if let Err(e) = setup() {
+                    tx.send(Err(e.to_string())).unwrap();
+                    return None;
+                }
+                tx.send(Ok(())).unwrap();
+
+                // A real time worker spinning already would keep the ones
+                // still being set up on its CPU from ever reporting
+                while !start.load(Ordering::Acquire) {
+                    thread::park();
+                }
+
+                let tid = gettid();
+                let start_migrations = nr_migrations(tid);
+                let task_start = TaskStats::read(tid);
+                let mut cpu = current_cpu();
+                let mut observed_migrations = 0;
+                let mut cpu_iterations = vec![0; core_mask.iter().max().map_or(0, |max| max + 1)];
+                let mut last_check = 0;
+                let mut duty_cycle = duty_config.map(|config| DutyCycle::new(config, tid));
+
+                let mut tick = |n: u64| {
+                    progress.publish(id, n);
+
+                    // Iterations since the last check are credited to the
+                    // CPU we are on now
+                    let now = current_cpu();
+                    if let Some(count) = cpu_iterations.get_mut(now as usize) {
+                        *count += n - last_check;
+                    }
+                    last_check = n;
+
+                    if now != cpu {
+                        observed_migrations += 1;
+                        cpu = now;
+                    }
+                };
+
+                let start = Instant::now();
+                let n = workload.run(&myquit, &mut |n| {
+                    tick(n);
+                    if let Some(duty_cycle) = &mut duty_cycle {
+                        duty_cycle.tick(&myquit);
+                    }
+                });
+                let elapsed = start.elapsed();
+                tick(n);
+
+                let task = task_start
+                    .zip(TaskStats::read(tid))
+                    .map(|(start, end)| end.since(&start));
+
+                Some(WorkerReport {
+                    id,
+                    policy,
+                    iterations: n,
+                    elapsed,
+                    cpu_iterations: cpu_iterations
+                        .into_iter()
+                        .enumerate()
+                        .filter(|&(_, count)| count > 0)
+                        .collect(),
+                    observed_migrations,
+                    kernel_migrations: start_migrations
+                        .zip(nr_migrations(tid))
+                        .map(|(start, end)| end - start),
+                    task,
+                    workload: workload.report(n),
+                    duty_cycle: duty_cycle
+                        .map(|duty| duty.finish(task.and_then(|t| t.runtime), elapsed)),
+                })
+            })
+        })
+        .collect();
+
+    let setup: Result<Vec<()>, String> = (0..num_threads).map(|_| rx.recv().unwrap()).collect();
+
+    // Let the workers go, only to quit right away if any failed
+    if setup.is_err() {
+        quit.store(true, Ordering::Release);
+    }
+    start.store(true, Ordering::Release);
+    for handle in &handles {
+        handle.thread().unpark();
+    }
+    setup?;
+
+    Ok(Workers { kicker, handles })
+}
+
+/// Jain's fairness index: 1 if every value is the same, down to 1/n if a
+/// single one gets everything.
+fn jain_index(values: &[f64]) -> f64 {
+    let sum: f64 = values.iter().sum();
+    let sum_squares: f64 = values.iter().map(|x| x * x).sum();
+
+    if sum_squares == 0.0 {
+        return 1.0;
+    }
+    sum * sum / (values.len() as f64 * sum_squares)
+}
+
+/// Ratio between the slowest and the fastest value.
+fn min_max_ratio(values: &[f64]) -> f64 {
+    let min = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let max = values.iter().copied().fold(0.0, f64::max);
+
+    if max == 0.0 {
+        return 1.0;
+    }
+    min / max
+}
+
+/// Iterations per second done on each CPU, by all the workers together.
+fn cpu_throughput(reports: &[WorkerReport]) -> Vec<(usize, f64)> {
+    let mut cpus: Vec<(usize, u64)> = Vec::new();
+
+    for &(cpu, count) in reports.iter().flat_map(|r| &r.cpu_iterations) {
+        match cpus.iter_mut().find(|(c, _)| *c == cpu) {
+            Some((_, total)) => *total += count,
+            None => cpus.push((cpu, count)),
+        }
+    }
+    cpus.sort_unstable();
+
+    let secs = reports
+        .iter()
+        .map(|r| r.elapsed)
+        .max()
+        .unwrap_or_default()
+        .as_secs_f64();
+
+    cpus.into_iter()
+        .map(|(cpu, count)| {
+            let rate = if secs == 0.0 {
+                0.0
+            } else {
+                count as f64 / secs
+            };
+            (cpu, rate)
+        })
+        .collect()
+}
+
+fn counters_to_json(counters: &[(&str, u64)]) -> Value {
+    Value::Object(
+        counters
+            .iter()
+            .map(|&(name, count)| (name.to_string(), json!(count)))
+            .collect(),
+    )
+}
+
+/// Workload counters summed over all the workers.
+fn total_counters(reports: &[WorkerReport]) -> Vec<(&'static str, u64)> {
+    let mut totals: Vec<(&'static str, u64)> = Vec::new();
+
+    for &(name, count) in reports.iter().flat_map(|r| &r.workload) {
+        match totals.iter_mut().find(|(n, _)| *n == name) {
+            Some((_, total)) => *total += count,
+            None => totals.push((name, count)),
+        }
+    }
+
+    totals
+}
+
+/// Number of workers and average iterations per second of each worker
+/// policy.
+fn policy_throughput(reports: &[WorkerReport]) -> Vec<(WorkerPolicy, usize, f64)> {
+    let mut policies: Vec<(WorkerPolicy, usize, f64)> = Vec::new();
+
+    for report in reports {
+        match policies.iter_mut().find(|(p, _, _)| *p == report.policy) {
+            Some((_, workers, total)) => {
+                *workers += 1;
+                *total += report.throughput();
+            }
+            None => policies.push((report.policy, 1, report.throughput())),
+        }
+    }
+
+    policies
+        .into_iter()
+        .map(|(policy, workers, total)| (policy, workers, total / workers as f64))
+        .collect()
+}
+
+fn format_kernel(migrations: Option<u64>) -> String {
+    migrations.map_or_else(|| "n/a".to_string(), |n| n.to_string())
+}
+
+fn format_msecs(ns: Option<u64>) -> String {
+    ns.map_or_else(
+        || "n/a".to_string(),
+        |ns| format!("{:.3}ms", ns as f64 / 1e6),
+    )
+}
+
+fn total_kernel(reports: &[WorkerReport]) -> Option<u64> {
+    reports.iter().map(|r| r.kernel_migrations).sum()
+}
+
+pub fn write_worker_reports(out: &mut dyn Write, reports: &[WorkerReport]) -> io::Result<()> {
+    if reports.is_empty() {
+        return Ok(());
+    }
+
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{:>6} {:>9} {:>14} {:>12} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}",
+        "Worker",
+        "Policy",
+        "Iterations",
+        "Miter/s",
+        "Observed",
+        "Kernel",
+        "Vol CS",
+        "Invol CS",
+        "Runtime",
+        "Wait"
+    )?;
+    for report in reports {
+        let (voluntary, involuntary, runtime, wait) = match &report.task {
+            Some(task) => (
+                task.voluntary_switches.to_string(),
+                task.involuntary_switches.to_string(),
+                format_msecs(task.runtime),
+                format_msecs(task.wait),
+            ),
+            None => (
+                "n/a".to_string(),
+                "n/a".to_string(),
+                "n/a".to_string(),
+                "n/a".to_string(),
+            ),
+        };
+
+        writeln!(
+            out,
+            "{:>6} {:>9} {:>14} {:>12.3} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}",
+            report.id,
+            report.policy.to_string(),
+            report.iterations,
+            report.throughput() / 1e6,
+            report.observed_migrations,
+            format_kernel(report.kernel_migrations),
+            voluntary,
+            involuntary,
+            runtime,
+            wait
+        )?;
+    }
+
+    if reports.iter().any(|r| r.duty_cycle.is_some()) {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>6} {:>10} {:>10}",
+            "Worker", "Requested", "Achieved"
+        )?;
+        for report in reports {
+            if let Some(duty) = report.duty_cycle {
+                let achieved = duty
+                    .achieved
+                    .map_or_else(|| "n/a".to_string(), |a| format!("{:.2}%", a * 100.0));
+                writeln!(
+                    out,
+                    "{:>6} {:>9.2}% {:>10}",
+                    report.id,
+                    duty.requested * 100.0,
+                    achieved
+                )?;
+            }
+        }
+    }
+
+    let policies = policy_throughput(reports);
+    if policies.len() > 1 {
+        writeln!(out)?;
+        writeln!(
+            out,
+            "{:>9} {:>8} {:>16}",
+            "Policy", "Workers", "Miter/s/worker"
+        )?;
+        for (policy, workers, rate) in policies {
+            writeln!(
+                out,
+                "{:>9} {:>8} {:>16.3}",
+                policy.to_string(),
+                workers,
+                rate / 1e6
+            )?;
+        }
+    }
+
+    writeln!(out)?;
+    writeln!(out, "{:>6} {:>12}", "CPU", "Miter/s")?;
+    for (cpu, rate) in cpu_throughput(reports) {
+        writeln!(out, "{:>6} {:>12.3}", cpu, rate / 1e6)?;
+    }
+
+    let throughput: Vec<f64> = reports.iter().map(WorkerReport::throughput).collect();
+    let observed: u64 = reports.iter().map(|r| r.observed_migrations).sum();
+    writeln!(out)?;
+    writeln!(
+        out,
+        "Worker fairness: Jain index {:.4}, min/max ratio {:.4}",
+        jain_index(&throughput),
+        min_max_ratio(&throughput)
+    )?;
+    writeln!(
+        out,
+        "Worker migrations: {} observed, {} by the kernel",
+        observed,
+        format_kernel(total_kernel(reports))
+    )?;
+
+    let secs = reports
+        .iter()
+        .map(|r| r.elapsed)
+        .max()
+        .unwrap_or_default()
+        .as_secs_f64();
+    for (name, count) in total_counters(reports) {
+        let rate = if secs == 0.0 {
+            0.0
+        } else {
+            count as f64 / secs
+        };
+        writeln!(
+            out,
+            "Workload {}: {} total, {:.0} per second",
+            name, count, rate
+        )?;
+    }
+
+    Ok(())
+}
+
+pub fn to_json(reports: &[WorkerReport]) -> Value {
+    let threads: Vec<Value> = reports.iter().map(WorkerReport::to_json).collect();
+    let cpus: Vec<Value> = cpu_throughput(reports)
+        .into_iter()
+        .map(|(cpu, rate)| json!({ "cpu": cpu, "iterations_per_sec": rate }))
+        .collect();
+    let throughput: Vec<f64> = reports.iter().map(WorkerReport::throughput).collect();
+    let policies: Vec<Value> = policy_throughput(reports)
+        .into_iter()
+        .map(|(policy, workers, rate)| {
+            json!({
+                "policy": policy.to_string(),
+                "workers": workers,
+                "iterations_per_sec": rate,
+            })
+        })
+        .collect();
+
+    json!({
+        "threads": threads,
+        "cpus": cpus,
+        "policies": policies,
+        "fairness": {
+            "jain_index": jain_index(&throughput),
+            "min_max_ratio": min_max_ratio(&throughput),
+        },
+        "migrations": {
+            "observed": reports.iter().map(|r| r.observed_migrations).sum::<u64>(),
+            "kernel": total_kernel(reports),
+        },
+        "workload": counters_to_json(&total_counters(reports)),
+    })
+}
@@ -0,0 +1,493 @@
+use clap::ArgEnum;
+use errno::errno;
+use serde::Serialize;
+use std::error::Error;
+use std::fs::File;
+use std::hint::black_box;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::Arc;
+use std::{cmp, ptr};
+use volatile::Volatile;
+
+/// Iterations of a workload between two calls to the progress callback.
+const TICK_ITERATIONS: u64 = 1024;
+
+/// Load a worker thread puts on its CPU.
+#[derive(ArgEnum, Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum WorkloadKind {
+    /// Integer increment loop
+    Alu,
+    /// Floating point multiply-add over a vectorizable array
+    Fp,
+    /// Sequential copy through the working set, memory bandwidth bound
+    /// if it does not fit in the caches
+    Memory,
+    /// Random cache line accesses over the working set
+    Cache,
+    /// Cheap system call in a loop
+    Syscall,
+    /// Groups of threads passing a message around through pipes
+    Pipe,
+    /// Groups of threads waking each other through eventfds
+    Eventfd,
+    /// Groups of threads waking each other through futexes
+    Futex,
+}
+
+/// Settings of the worker workload.
+#[derive(Clone, Copy, Debug)]
+pub struct WorkloadConfig {
+    pub kind: WorkloadKind,
+    /// Working set of the memory and cache workloads, in bytes.
+    pub working_set: usize,
+    /// Threads taking turns in the pipe, eventfd and futex workloads.
+    pub group_size: usize,
+    /// Bytes passed between the threads of the pipe workload.
+    pub message_size: usize,
+}
+
+/// What a worker thread does while the test runs.
+pub trait Workload: Send {
+    /// Allocates and initializes whatever the workload needs. Called from
+    /// the worker thread itself, so memory is first touched by the thread
+    /// that uses it.
+    fn setup(&mut self) {}
+
+    /// Does work until `quit` is set, calling `tick` every TICK_ITERATIONS
+    /// iterations with the iterations done so far. Returns the total.
+    fn run(&mut self, quit: &AtomicBool, tick: &mut dyn FnMut(u64)) -> u64;
+
+    /// Work done, besides the loop iterations, as (name, count).
+    fn report(&self, _iterations: u64) -> Vec<(&'static str, u64)> {
+        Vec::new()
+    }
+}
+
+/// Pseudo random number generator, cheap enough not to hide the cache
+/// misses of the cache workload. `state` must not be zero.
+pub fn xorshift64(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+/// The loop shared by all workloads: runs `step` until `quit` is set.
+fn run_until_quit(quit: &AtomicBool, tick: &mut dyn FnMut(u64), mut step: impl FnMut()) -> u64 {
+    let mut n: u64 = 0;
+
+    while !quit.load(Ordering::Acquire) {
+        step();
+        n += 1;
+        if n.is_multiple_of(TICK_ITERATIONS) {
+            tick(n);
+        }
+    }
+
+    n
+}
+
+struct AluWorkload;
+
+impl Workload for AluWorkload {
+    fn run(&mut self, quit: &AtomicBool, tick: &mut dyn FnMut(u64)) -> u64 {
+        let mut dummy: u64 = 0;
+        let mut volatile_dummy = Volatile::new(&mut dummy);
+
+        run_until_quit(quit, tick, || {
+            // just useless computation
+            volatile_dummy.write(volatile_dummy.read().wrapping_add(1));
+        })
+    }
+}
+
+const FP_LANES: usize = 64;
+
+struct FpWorkload;
+
+impl Workload for FpWorkload {
+    fn run(&mut self, quit: &AtomicBool, tick: &mut dyn FnMut(u64)) -> u64 {
+        let mut lanes = [1.0f64; FP_LANES];
+
+        run_until_quit(quit, tick, || {
+            // Converges to 2.0, so the values never overflow
+            for x in lanes.iter_mut() {
+                *x = *x * 0.5 + 1.0;
+            }
+            black_box(&mut lanes);
+        })
+    }
+
+    fn report(&self, iterations: u64) -> Vec<(&'static str, u64)> {
+        vec![("flops", iterations * FP_LANES as u64 * 2)]
+    }
+}
+
+/// Bytes copied per iteration by the memory workload.
+const MEMORY_CHUNK: usize = 4096;
+
+struct MemoryWorkload {
+    size: usize,
+    buffer: Vec<u8>,
+}
+
+impl Workload for MemoryWorkload {
+    fn setup(&mut self) {
+        // Copy from one half of the buffer to the other
+        self.buffer = vec![1; (self.size / 2 / MEMORY_CHUNK).max(1) * MEMORY_CHUNK * 2];
+    }
+
+    fn run(&mut self, quit: &AtomicBool, tick: &mut dyn FnMut(u64)) -> u64 {
+        let half = self.buffer.len() / 2;
+        let (src, dst) = self.buffer.split_at_mut(half);
+        let mut offset = 0;
+
+        run_until_quit(quit, tick, || {
+            let chunk = offset..offset + MEMORY_CHUNK;
+            dst[chunk.clone()].copy_from_slice(&src[chunk]);
+            black_box(&mut *dst);
+            offset = (offset + MEMORY_CHUNK) % src.len();
+        })
+    }
+
+    fn report(&self, iterations: u64) -> Vec<(&'static str, u64)> {
+        // Every byte is read once and written once
+        vec![("bytes", iterations * MEMORY_CHUNK as u64 * 2)]
+    }
+}
+
+const CACHE_LINE: usize = 64;
+/// Cache lines touched per iteration by the cache workload.
+const CACHE_ACCESSES: u64 = 16;
+
+struct CacheWorkload {
+    size: usize,
+    lines: Vec<u64>,
+}
+
+impl Workload for CacheWorkload {
+    fn setup(&mut self) {
+        let words = (self.size / 8).max(CACHE_LINE / 8);
+        self.lines = vec![0; words];
+    }
+
+    fn run(&mut self, quit: &AtomicBool, tick: &mut dyn FnMut(u64)) -> u64 {
+        let lines = &mut self.lines;
+        let num_lines = lines.len() / (CACHE_LINE / 8);
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+
+        run_until_quit(quit, tick, || {
+            for _ in 0..CACHE_ACCESSES {
+                let line = (xorshift64(&mut state) % num_lines as u64) as usize;
+                lines[line * (CACHE_LINE / 8)] += 1;
+            }
+            black_box(&mut *lines);
+        })
+    }
+
+    fn report(&self, iterations: u64) -> Vec<(&'static str, u64)> {
+        vec![("cache_lines", iterations * CACHE_ACCESSES)]
+    }
+}
+
+struct SyscallWorkload;
+
+impl Workload for SyscallWorkload {
+    fn run(&mut self, quit: &AtomicBool, tick: &mut dyn FnMut(u64)) -> u64 {
+        run_until_quit(quit, tick, || {
+            // Called directly, so libc cannot cache the result
+            unsafe { libc::syscall(libc::SYS_getppid) };
+        })
+    }
+
+    fn report(&self, iterations: u64) -> Vec<(&'static str, u64)> {
+        vec![("syscalls", iterations)]
+    }
+}
+
+/// One end of the ring of a ping-pong group. A thread waits for its turn
+/// on its own channel and then passes it on to the next thread's channel.
+trait Channel: Send {
+    fn recv(&mut self) -> io::Result<()>;
+    fn send(&mut self) -> io::Result<()>;
+}
+
+struct PipeChannel {
+    rx: File,
+    tx: File,
+    message: Vec<u8>,
+}
+
+impl Channel for PipeChannel {
+    fn recv(&mut self) -> io::Result<()> {
+        self.rx.read_exact(&mut self.message)
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        self.tx.write_all(&self.message)
+    }
+}
+
+struct EventfdChannel {
+    rx: Arc<File>,
+    tx: Arc<File>,
+}
+
+impl Channel for EventfdChannel {
+    fn recv(&mut self) -> io::Result<()> {
+        let mut value = [0u8; 8];
+        (&*self.rx).read_exact(&mut value)
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        (&*self.tx).write_all(&1u64.to_ne_bytes())
+    }
+}
+
+fn futex(word: &AtomicU32, op: libc::c_int, val: u32) -> io::Result<()> {
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_futex,
+            word.as_ptr(),
+            op | libc::FUTEX_PRIVATE_FLAG,
+            val,
+            ptr::null::<libc::timespec>(),
+        )
+    };
+
+    if ret < 0 && errno().0 != libc::EAGAIN && errno().0 != libc::EINTR {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Set to 1 when it is the thread's turn.
+struct FutexChannel {
+    rx: Arc<AtomicU32>,
+    tx: Arc<AtomicU32>,
+}
+
+impl Channel for FutexChannel {
+    fn recv(&mut self) -> io::Result<()> {
+        while self.rx.swap(0, Ordering::Acquire) == 0 {
+            futex(&self.rx, libc::FUTEX_WAIT, 0)?;
+        }
+        Ok(())
+    }
+
+    fn send(&mut self) -> io::Result<()> {
+        self.tx.store(1, Ordering::Release);
+        futex(&self.tx, libc::FUTEX_WAKE, 1)
+    }
+}
+
+/// Hackbench style workload: the threads of a group pass a single token
+/// around a ring, so every iteration is a cross thread wakeup. A thread may
+/// see quit right after passing the token on and leave without ever passing
+/// it again, so the threads still waiting are woken up by a Kicker.
+struct PingPongWorkload {
+    channel: Box<dyn Channel>,
+    message_size: usize,
+}
+
+impl Workload for PingPongWorkload {
+    fn run(&mut self, quit: &AtomicBool, tick: &mut dyn FnMut(u64)) -> u64 {
+        let channel = &mut self.channel;
+
+        run_until_quit(quit, tick, || {
+            // Once quitting, the pipe of a thread that already left may be
+            // closed
+            if let Err(e) = channel.recv().and_then(|_| channel.send()) {
+                assert!(quit.load(Ordering::Acquire), "{}", e);
+            }
+        })
+    }
+
+    fn report(&self, iterations: u64) -> Vec<(&'static str, u64)> {
+        if self.message_size == 0 {
+            vec![("wakeups", iterations)]
+        } else {
+            vec![
+                ("messages", iterations),
+                ("bytes", iterations * self.message_size as u64),
+            ]
+        }
+    }
+}
+
+/// Wakes up the threads of the ping-pong groups left waiting for a token
+/// when the test quits.
+#[derive(Default)]
+pub struct Kicker {
+    eventfds: Vec<Arc<File>>,
+    futexes: Vec<Arc<AtomicU32>>,
+}
+
+impl Kicker {
+    /// Gives every thread a token of its own. Only call it once quit is
+    /// set, so each thread takes at most one more turn. Pipes need no kick:
+    /// the only write end of a pipe belongs to the previous thread of the
+    /// ring, so it reads EOF once that thread is gone.
+    pub fn kick(&self) {
+        for fd in &self.eventfds {
+            let _ = (&**fd).write_all(&1u64.to_ne_bytes());
+        }
+        for word in &self.futexes {
+            word.store(1, Ordering::Release);
+            let _ = futex(word, libc::FUTEX_WAKE, 1);
+        }
+    }
+}
+
+fn new_pipe() -> Result<(File, File), Box<dyn Error>> {
+    let mut fds = [0; 2];
+    if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } < 0 {
+        return Err(Box::new(errno()));
+    }
+    Ok(unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+/// Default capacity of a pipe, bigger messages need a bigger pipe or the
+/// first thread would block writing the token.
+const PIPE_CAPACITY: usize = 65536;
+
+fn new_eventfd() -> Result<Arc<File>, Box<dyn Error>> {
+    let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(Box::new(errno()));
+    }
+    Ok(Arc::new(unsafe { File::from_raw_fd(fd) }))
+}
+
+/// Creates the channels of a ring of `size` threads, with the token given
+/// to the first one.
+fn new_ring(
+    config: &WorkloadConfig,
+    size: usize,
+    kicker: &mut Kicker,
+) -> Result<Vec<Box<dyn Channel>>, Box<dyn Error>> {
+    let mut channels: Vec<Box<dyn Channel>> = Vec::new();
+
+    match config.kind {
+        WorkloadKind::Pipe => {
+            let (mut rxs, mut txs): (Vec<File>, Vec<File>) = (0..size)
+                .map(|_| new_pipe())
+                .collect::<Result<Vec<_>, _>>()?
+                .into_iter()
+                .unzip();
+            if config.message_size > PIPE_CAPACITY {
+                for tx in &txs {
+                    let ret = unsafe {
+                        libc::fcntl(tx.as_raw_fd(), libc::F_SETPIPE_SZ, config.message_size)
+                    };
+                    if ret < 0 {
+                        return Err(format!("cannot resize the pipes: {}", errno()).into());
+                    }
+                }
+            }
+
+            let message = vec![0; config.message_size];
+            txs[0].write_all(&message)?;
+            // Thread i writes to the pipe thread i + 1 reads from
+            txs.rotate_left(1);
+            for (rx, tx) in rxs.drain(..).zip(txs.drain(..)) {
+                channels.push(Box::new(PipeChannel {
+                    rx,
+                    tx,
+                    message: message.clone(),
+                }));
+            }
+        }
+        WorkloadKind::Eventfd => {
+            let fds = (0..size)
+                .map(|_| new_eventfd())
+                .collect::<Result<Vec<_>, _>>()?;
+            (&*fds[0]).write_all(&1u64.to_ne_bytes())?;
+            kicker.eventfds.extend(fds.iter().cloned());
+            for i in 0..size {
+                channels.push(Box::new(EventfdChannel {
+                    rx: fds[i].clone(),
+                    tx: fds[(i + 1) % size].clone(),
+                }));
+            }
+        }
+        WorkloadKind::Futex => {
+            let words: Vec<Arc<AtomicU32>> =
+                (0..size).map(|_| Arc::new(AtomicU32::new(0))).collect();
+            words[0].store(1, Ordering::Release);
+            kicker.futexes.extend(words.iter().cloned());
+            for i in 0..size {
+                channels.push(Box::new(FutexChannel {
+                    rx: words[i].clone(),
+                    tx: words[(i + 1) % size].clone(),
+                }));
+            }
+        }
+        _ => unreachable!(),
+    }
+
+    Ok(channels)
+}
+
+/// Creates the workloads of `count` worker threads. Workloads where threads
+/// wake each other are set up in groups of `group_size` consecutive
+/// threads; the last group may be smaller. Their channels are added to
+/// `kicker`.
+pub fn new_workloads(
+    config: &WorkloadConfig,
+    count: usize,
+    kicker: &mut Kicker,
+) -> Result<Vec<Box<dyn Workload>>, Box<dyn Error>> {
+    let size = config.working_set;
+    let mut workloads: Vec<Box<dyn Workload>> = Vec::new();
+
+    match config.kind {
+        WorkloadKind::Pipe | WorkloadKind::Eventfd | WorkloadKind::Futex => {
+            if config.group_size == 0 {
+                return Err("the group size must be at least 1".into());
+            }
+            if config.kind == WorkloadKind::Pipe && config.message_size == 0 {
+                return Err("the message size must be at least 1 byte".into());
+            }
+
+            let message_size = match config.kind {
+                WorkloadKind::Pipe => config.message_size,
+                _ => 0,
+            };
+            let mut left = count;
+            while left > 0 {
+                let group = cmp::min(left, config.group_size);
+                for channel in new_ring(config, group, kicker)? {
+                    workloads.push(Box::new(PingPongWorkload {
+                        channel,
+                        message_size,
+                    }));
+                }
+                left -= group;
+            }
+        }
+        _ => {
+            for _ in 0..count {
+                workloads.push(match config.kind {
+                    WorkloadKind::Alu => Box::new(AluWorkload),
+                    WorkloadKind::Fp => Box::new(FpWorkload),
+                    WorkloadKind::Memory => Box::new(MemoryWorkload {
+                        size,
+                        buffer: Vec::new(),
+                    }),
+                    WorkloadKind::Cache => Box::new(CacheWorkload {
+                        size,
+                        lines: Vec::new(),
+                    }),
+                    WorkloadKind::Syscall => Box::new(SyscallWorkload),
+                    _ => unreachable!(),
+                });
+            }
+        }
+    }
+
+    Ok(workloads)
+}